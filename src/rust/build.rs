@@ -0,0 +1,15 @@
+// Exposes the resolved version of the fsrs dependency as FSRS_CRATE_VERSION,
+// read from Cargo.lock, so R can report which fsrs-rs release is compiled in.
+use std::fs;
+
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let version = lock
+        .split("[[package]]")
+        .find(|pkg| pkg.contains("\nname = \"fsrs\"\n"))
+        .and_then(|pkg| pkg.lines().find_map(|line| line.strip_prefix("version = \"")))
+        .map(|v| v.trim_end_matches('"').to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=FSRS_CRATE_VERSION={}", version);
+}
@@ -1,16 +1,106 @@
 use extendr_api::prelude::*;
-use fsrs::{FSRS, MemoryState, DEFAULT_PARAMETERS, FSRSItem, FSRSReview, ComputeParametersInput};
-
-const DECAY: f64 = -0.5;
-const FACTOR: f64 = 19.0 / 81.0;
+use fsrs::{FSRS, MemoryState, DEFAULT_PARAMETERS, FSRSItem, FSRSReview, ComputeParametersInput, SimulatorConfig, PostSchedulingFn, simulate, CombinedProgressState, FSRSError};
+use fsrs::Card as SimulatorCard;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 // ============================================================================
 // PARAMETERS
 // ============================================================================
 
 #[extendr]
-fn fsrs_default_parameters() -> Vec<f64> {
-    DEFAULT_PARAMETERS.iter().map(|&x| x as f64).collect()
+fn fsrs_default_parameters() -> Robj {
+    named_params(&DEFAULT_PARAMETERS)
+}
+
+/// Release of the fsrs crate compiled in (set by build.rs from Cargo.lock)
+/// and the FSRS model its default parameters belong to.
+#[extendr]
+fn fsrs_version() -> List {
+    list!(
+        algorithm = "FSRS-6",
+        n_params = DEFAULT_PARAMETERS.len() as i32,
+        crate_version = env!("FSRS_CRATE_VERSION")
+    )
+}
+
+/// Lower and upper bound of each weight, as clipped by the fsrs-rs optimizer
+/// (`clip_parameters` with one relearning step and short-term memory
+/// enabled). The crate keeps the table private, so it is mirrored here.
+const PARAMETER_BOUNDS: [(f64, f64); 21] = [
+    (0.001, 100.0),
+    (0.001, 100.0),
+    (0.001, 100.0),
+    (0.001, 100.0),
+    (1.0, 10.0),
+    (0.001, 4.0),
+    (0.001, 4.0),
+    (0.001, 0.75),
+    (0.0, 4.5),
+    (0.0, 0.8),
+    (0.001, 3.5),
+    (0.001, 5.0),
+    (0.001, 0.25),
+    (0.001, 0.9),
+    (0.0, 4.0),
+    (0.0, 1.0),
+    (1.0, 6.0),
+    (0.0, 2.0),
+    (0.0, 2.0),
+    (0.01, 0.8),
+    (0.1, 0.8),
+];
+
+/// Bounds for a vector of `n_params` weights (21, 19 or 17) as fsrs-rs clips
+/// it. Without short-term memory the floor of w19 is 0. FSRS-4.5 weights are
+/// converted before clipping (`check_and_fill_parameters`), so w5 and w6 get
+/// the bounds that map onto the FSRS-6 ones, and w4, whose initial
+/// difficulty `w4 + 2 * w5` is what gets clipped, has none of its own (NaN).
+fn parameter_bounds(n_params: usize, enable_short_term: bool) -> Vec<(f64, f64)> {
+    let mut bounds = PARAMETER_BOUNDS.to_vec();
+    if !enable_short_term {
+        bounds[19].0 = 0.0;
+    }
+    if n_params == 17 {
+        let (low, high) = PARAMETER_BOUNDS[5];
+        bounds[4] = (f64::NAN, f64::NAN);
+        bounds[5] = (((3.0 * low).exp() - 1.0) / 3.0, ((3.0 * high).exp() - 1.0) / 3.0);
+        bounds[6] = (PARAMETER_BOUNDS[6].0 - 0.5, PARAMETER_BOUNDS[6].1 - 0.5);
+    }
+    bounds.truncate(n_params);
+    bounds
+}
+
+#[extendr]
+fn fsrs_parameter_bounds(n_params: i32, enable_short_term: bool) -> List {
+    let bounds = parameter_bounds(n_params as usize, enable_short_term);
+    list!(
+        lower = bounds.iter().map(|b| b.0).collect::<Vec<f64>>(),
+        upper = bounds.iter().map(|b| b.1).collect::<Vec<f64>>()
+    )
+}
+
+#[extendr]
+fn fsrs_clamp_parameters(params: Vec<f64>, enable_short_term: bool) -> List {
+    let bounds = parameter_bounds(params.len(), enable_short_term);
+    let mut clamped: Vec<f64> = params.iter()
+        .zip(&bounds)
+        .map(|(&w, &(low, high))| if low.is_nan() { w } else { w.clamp(low, high) })
+        .collect();
+    if params.len() == 17 {
+        // FSRS-4.5 w4 keeps the initial difficulty w4 + 2 * w5 in range
+        let (low, high) = PARAMETER_BOUNDS[4];
+        clamped[4] = clamped[4].clamp(low - 2.0 * clamped[5], high - 2.0 * clamped[5]);
+    }
+    let changed: Vec<i32> = params.iter()
+        .zip(&clamped)
+        .enumerate()
+        .filter(|(_, (w, c))| w != c)
+        .map(|(i, _)| i as i32 + 1)
+        .collect();
+    list!(parameters = clamped, changed = changed)
 }
 
 // ============================================================================
@@ -18,27 +108,161 @@ fn fsrs_default_parameters() -> Vec<f64> {
 // ============================================================================
 
 #[extendr]
-fn fsrs_next_interval(stability: f64, desired_retention: f64, params: Option<Vec<f64>>) -> f64 {
-    let fsrs = create_fsrs(params);
-    fsrs.next_interval(Some(stability as f32), desired_retention as f32, 0) as f64
+fn fsrs_next_interval(
+    stability: Option<f64>,
+    desired_retention: f64,
+    params: Option<Vec<f64>>,
+    max_interval: f64,
+    round: bool,
+    rating: i32
+) -> Result<f64> {
+    check_retention("desired_retention", desired_retention)?;
+    let fsrs = create_fsrs(params)?;
+    Ok(next_interval(&fsrs, stability, desired_retention, max_interval, round, rating))
+}
+
+/// Errors unless `retention` is strictly between 0 and 1. fsrs-rs computes
+/// intervals from `ln(retention)`, so 0 and 1 give infinite or zero
+/// intervals and values outside give NaN, which would reach R silently.
+fn check_retention(name: &str, retention: f64) -> Result<()> {
+    if retention > 0.0 && retention < 1.0 {
+        Ok(())
+    } else {
+        Err(Error::Other(format!("{} must be in (0, 1), got {}", name, retention)))
+    }
+}
+
+fn next_interval(
+    fsrs: &FSRS,
+    stability: Option<f64>,
+    desired_retention: f64,
+    max_interval: f64,
+    round: bool,
+    rating: i32
+) -> f64 {
+    // fsrs-rs reads `rating` only when `stability` is None, to look up the
+    // initial stability of a new card rated `rating` (w[rating - 1]). With a
+    // stability it is ignored, which is why the other calls here pass 0.
+    let interval = fsrs.next_interval(
+        stability.map(|s| s as f32),
+        desired_retention as f32,
+        (rating as u32).min(4).max(1)
+    ) as f64;
+    // Round to whole days (at least 1) before capping, as Anki does
+    let interval = if round { interval.round().max(1.0) } else { interval };
+    interval.min(max_interval)
+}
+
+/// Interval for one stability at each of several desired retentions, rounded
+/// and capped as in fsrs_next_interval.
+#[extendr]
+fn fsrs_intervals_for_retentions(
+    stability: f64,
+    retentions: Vec<f64>,
+    params: Option<Vec<f64>>,
+    max_interval: f64,
+    round: bool
+) -> Result<Vec<f64>> {
+    for &retention in &retentions {
+        check_retention("retentions", retention)?;
+    }
+    let fsrs = create_fsrs(params)?;
+    Ok(retentions.iter()
+        .map(|&retention| next_interval(&fsrs, Some(stability), retention, max_interval, round, 0))
+        .collect())
+}
+
+#[extendr]
+fn fsrs_next_interval_fuzz(
+    stability: f64,
+    desired_retention: f64,
+    params: Option<Vec<f64>>,
+    seed: i32,
+    max_interval: i32
+) -> Result<i32> {
+    check_retention("desired_retention", desired_retention)?;
+    let fsrs = create_fsrs(params)?;
+    let interval = fsrs.next_interval(Some(stability as f32), desired_retention as f32, 0) as f64;
+    // Like Anki, cap the whole-day fuzzed interval rather than the raw one
+    Ok(fuzz_interval(interval, uniform_from_seed(seed as u64)).min(max_interval))
 }
 
 #[extendr]
-fn fsrs_initial_state(rating: i32, params: Option<Vec<f64>>) -> List {
-    let fsrs = create_fsrs(params);
+fn fsrs_initial_state(
+    rating: i32,
+    params: Option<Vec<f64>>,
+    short_term: bool,
+    difficulty_override: Option<f64>
+) -> Result<List> {
+    let fsrs = create_fsrs(params)?;
+    initial_state(&fsrs, rating, short_term, difficulty_override)
+}
+
+fn initial_state(
+    fsrs: &FSRS,
+    rating: i32,
+    short_term: bool,
+    difficulty_override: Option<f64>
+) -> Result<List> {
     let r = (rating as u32).min(4).max(1);
-    let states = fsrs.next_states(None, 0.0, 0).unwrap();
-    let state = match r {
-        1 => states.again.memory,
-        2 => states.hard.memory,
-        3 => states.good.memory,
-        4 => states.easy.memory,
-        _ => states.good.memory,
-    };
-    list!(
+    let states = memory_states(fsrs, None, 0)?;
+    let mut state = memory_for_rating(&states, r);
+    // A first Again sends the card to (re)learning steps on the same day;
+    // fsrs-rs models that as a short-term Again update of w0.
+    if short_term && r == 1 {
+        state = memory_states(fsrs, Some(state), 0)?.again.memory;
+    }
+    // Replaces the rating-derived difficulty outright; the stability is
+    // left as the model computed it
+    if let Some(d) = difficulty_override {
+        state.difficulty = d as f32;
+    }
+    Ok(list!(
         stability = state.stability as f64,
-        difficulty = state.difficulty as f64
-    )
+        difficulty = clamp_difficulty(state.difficulty)
+    ))
+}
+
+#[extendr]
+fn fsrs_new_card_interval(
+    rating: Vec<i32>,
+    desired_retention: f64,
+    params: Option<Vec<f64>>
+) -> Result<Vec<i32>> {
+    check_retention("desired_retention", desired_retention)?;
+    let fsrs = create_fsrs(params)?;
+    let states = next_states(&fsrs, None, desired_retention as f32, 0)?;
+    
+    Ok(rating.iter()
+        .map(|&r| {
+            let state = memory_for_rating(&states, (r as u32).min(4).max(1));
+            let interval = fsrs.next_interval(Some(state.stability), desired_retention as f32, 0);
+            (interval as f64).round().max(1.0) as i32
+        })
+        .collect())
+}
+
+#[extendr]
+fn fsrs_initial_states_all(desired_retention: f64, params: Option<Vec<f64>>) -> Result<List> {
+    let fsrs = create_fsrs(params)?;
+    let states = memory_states(&fsrs, None, 0)?;
+    
+    let mut stability = Vec::with_capacity(4);
+    let mut difficulty = Vec::with_capacity(4);
+    let mut interval = Vec::with_capacity(4);
+    for r in 1..=4 {
+        let state = memory_for_rating(&states, r);
+        stability.push(state.stability as f64);
+        difficulty.push(clamp_difficulty(state.difficulty));
+        interval.push(fsrs.next_interval(Some(state.stability), desired_retention as f32, 0) as f64);
+    }
+    
+    Ok(list!(
+        rating = (1..=4).collect::<Vec<i32>>(),
+        stability = stability,
+        difficulty = difficulty,
+        interval = interval
+    ))
 }
 
 #[extendr]
@@ -47,28 +271,58 @@ fn fsrs_next_state(
     difficulty: f64,
     elapsed_days: f64,
     rating: i32,
-    desired_retention: f64,
-    params: Option<Vec<f64>>
-) -> List {
-    let fsrs = create_fsrs(params);
+    params: Option<Vec<f64>>,
+    enable_short_term: bool
+) -> Result<List> {
+    let fsrs = create_fsrs(params)?;
+    next_state(&fsrs, stability, difficulty, elapsed_days, rating, enable_short_term)
+}
+
+fn next_state(
+    fsrs: &FSRS,
+    stability: f64,
+    difficulty: f64,
+    elapsed_days: f64,
+    rating: i32,
+    enable_short_term: bool
+) -> Result<List> {
     let state = MemoryState {
         stability: stability as f32,
         difficulty: difficulty as f32
     };
     let r = (rating as u32).min(4).max(1);
     let days = elapsed_days.max(0.0).round() as u32;
-    let states = fsrs.next_states(Some(state), desired_retention as f32, days).unwrap();
-    let next = match r {
-        1 => states.again.memory,
-        2 => states.hard.memory,
-        3 => states.good.memory,
-        4 => states.easy.memory,
-        _ => states.good.memory,
+    // fsrs-rs switches to its short-term update whenever days == 0; a
+    // long-term-only model ignores same-day reviews instead.
+    let next = if days == 0 && !enable_short_term {
+        state
+    } else {
+        memory_for_rating(&memory_states(fsrs, Some(state), days)?, r)
     };
-    list!(
+    Ok(list!(
         stability = next.stability as f64,
-        difficulty = next.difficulty as f64
-    )
+        difficulty = clamp_difficulty(next.difficulty)
+    ))
+}
+
+/// Ratio of post-review to current stability for Again, Hard, Good and Easy.
+#[extendr]
+fn fsrs_stability_multipliers(
+    stability: f64,
+    difficulty: f64,
+    elapsed_days: f64,
+    params: Option<Vec<f64>>,
+    enable_short_term: bool
+) -> Result<Vec<f64>> {
+    let fsrs = create_fsrs(params)?;
+    let state = MemoryState {
+        stability: stability as f32,
+        difficulty: difficulty as f32
+    };
+    let days = elapsed_days.max(0.0).round() as u32;
+    (1..=4)
+        .map(|r| Ok(review_state(&fsrs, Some(state), r, days, enable_short_term)?.stability as f64 / stability))
+        .collect()
 }
 
 #[extendr]
@@ -76,11 +330,31 @@ fn fsrs_repeat(
     stability: Option<f64>,
     difficulty: Option<f64>,
     elapsed_days: f64,
-    desired_retention: f64,
-    params: Option<Vec<f64>>
-) -> List {
-    let fsrs = create_fsrs(params);
-    
+    retention_for_scheduling: f64,
+    retention_for_interval: f64,
+    params: Option<Vec<f64>>,
+    enable_short_term: bool
+) -> Result<List> {
+    let fsrs = create_fsrs(params)?;
+    repeat(
+        &fsrs, stability, difficulty, elapsed_days,
+        retention_for_scheduling, retention_for_interval, enable_short_term
+    )
+}
+
+/// The four outcomes of reviewing a card now, shared by fsrs_repeat and
+/// fsrs_model_repeat.
+fn repeat(
+    fsrs: &FSRS,
+    stability: Option<f64>,
+    difficulty: Option<f64>,
+    elapsed_days: f64,
+    retention_for_scheduling: f64,
+    retention_for_interval: f64,
+    enable_short_term: bool
+) -> Result<List> {
+    check_retention("desired_retention", retention_for_scheduling)?;
+    check_retention("retention_for_interval", retention_for_interval)?;
     let state = match (stability, difficulty) {
         (Some(s), Some(d)) => Some(MemoryState {
             stability: s as f32,
@@ -89,268 +363,2794 @@ fn fsrs_repeat(
         _ => None,
     };
     
-    let days = elapsed_days.max(0.0).round() as u32;
-    let states = fsrs.next_states(state, desired_retention as f32, days).unwrap();
-    
-    let make_outcome = |item: &fsrs::ItemState| -> List {
+    let make_outcome = |memory: &MemoryState| -> List {
         let interval = fsrs.next_interval(
-            Some(item.memory.stability), 
-            desired_retention as f32, 
+            Some(memory.stability), 
+            retention_for_scheduling as f32, 
+            0
+        );
+        let preview_interval = fsrs.next_interval(
+            Some(memory.stability),
+            retention_for_interval as f32,
             0
         );
         list!(
-            stability = item.memory.stability as f64,
-            difficulty = item.memory.difficulty as f64,
-            interval = interval as f64
+            stability = memory.stability as f64,
+            difficulty = clamp_difficulty(memory.difficulty),
+            interval = interval as f64,
+            preview_interval = preview_interval as f64
         )
     };
     
-    list!(
-        again = make_outcome(&states.again),
-        hard = make_outcome(&states.hard),
-        good = make_outcome(&states.good),
-        easy = make_outcome(&states.easy)
-    )
+    let days = elapsed_days.max(0.0).round() as u32;
+    if let (Some(current), 0, false) = (state, days, enable_short_term) {
+        // Long-term-only: a same-day review leaves the memory state unchanged
+        return Ok(list!(
+            again = make_outcome(&current),
+            hard = make_outcome(&current),
+            good = make_outcome(&current),
+            easy = make_outcome(&current)
+        ));
+    }
+    let states = next_states(fsrs, state, retention_for_scheduling as f32, days)?;
+    
+    Ok(list!(
+        again = make_outcome(&states.again.memory),
+        hard = make_outcome(&states.hard.memory),
+        good = make_outcome(&states.good.memory),
+        easy = make_outcome(&states.easy.memory)
+    ))
+}
+
+/// One review of a card in (re)learning step `step` of `steps_minutes`.
+/// Again restarts at the first step, Hard repeats the current one, Good
+/// advances to the next and Easy graduates; Good on the last step graduates
+/// too, with an interval from the card's new stability.
+#[extendr]
+fn fsrs_apply_learning_steps(
+    rating: i32,
+    step: i32,
+    steps_minutes: Vec<f64>,
+    stability: Option<f64>,
+    difficulty: Option<f64>,
+    desired_retention: f64,
+    params: Option<Vec<f64>>
+) -> Result<List> {
+    let fsrs = create_fsrs(params)?;
+    let r = (rating as u32).min(4).max(1);
+    // 1-based like card_starts; clamp so an out-of-range step cannot panic
+    let current = (step.max(1) as usize - 1).min(steps_minutes.len().saturating_sub(1));
+    
+    let state = match (stability, difficulty) {
+        (Some(s), Some(d)) => Some(MemoryState {
+            stability: s as f32,
+            difficulty: d as f32,
+        }),
+        _ => None,
+    };
+    // Learning steps are minutes apart, so every step review is same-day
+    let states = next_states(&fsrs, state, desired_retention as f32, 0)?;
+    let memory = memory_for_rating(&states, r);
+    
+    // Anki's step rules: Again restarts, Hard repeats the step (the first
+    // step waits the mean of the first two), Good advances, Easy graduates
+    let next = match (r, steps_minutes.len()) {
+        (_, 0) | (4, _) => None,
+        (1, _) => Some((0, steps_minutes[0])),
+        (2, n) if current == 0 => Some((0, if n > 1 {
+            (steps_minutes[0] + steps_minutes[1]) / 2.0
+        } else {
+            (steps_minutes[0] * 1.5).min(steps_minutes[0] + 1440.0)
+        })),
+        (2, _) => Some((current, steps_minutes[current])),
+        (_, n) if current + 1 < n => Some((current + 1, steps_minutes[current + 1])),
+        _ => None,
+    };
+    
+    let (graduated, next_step, delay_minutes, interval) = match next {
+        Some((i, delay)) => (false, i as i32 + 1, delay, f64::na()),
+        None => {
+            let days = fsrs.next_interval(Some(memory.stability), desired_retention as f32, 0);
+            (true, i32::na(), f64::na(), (days as f64).round().max(1.0))
+        }
+    };
+    
+    Ok(list!(
+        graduated = graduated,
+        step = next_step,
+        delay_minutes = delay_minutes,
+        interval = interval,
+        stability = memory.stability as f64,
+        difficulty = clamp_difficulty(memory.difficulty)
+    ))
+}
+
+/// The lapse path: an Again on a review `elapsed_days` after the last one,
+/// then one same-day Good per relearning step, the last of which graduates
+/// the card back to FSRS intervals.
+#[extendr]
+fn fsrs_apply_relearning(
+    stability: f64,
+    difficulty: f64,
+    elapsed_days: Option<f64>,
+    relearn_steps_minutes: Vec<f64>,
+    desired_retention: f64,
+    params: Option<Vec<f64>>,
+    max_interval: f64
+) -> Result<List> {
+    let fsrs = create_fsrs(params)?;
+    let retention = desired_retention as f32;
+    let state = MemoryState {
+        stability: stability as f32,
+        difficulty: difficulty as f32
+    };
+    // Without elapsed days the card lapses on the day it was due
+    let elapsed = elapsed_days.unwrap_or_else(|| {
+        next_interval(&fsrs, Some(stability), desired_retention, max_interval, true, 0)
+    });
+    let lapsed = next_states(&fsrs, Some(state), retention, elapsed.max(0.0).round() as u32)?
+        .again
+        .memory;
+    
+    let mut memory = lapsed;
+    for _ in &relearn_steps_minutes {
+        memory = next_states(&fsrs, Some(memory), retention, 0)?.good.memory;
+    }
+    
+    Ok(list!(
+        stability = memory.stability as f64,
+        difficulty = clamp_difficulty(memory.difficulty),
+        post_lapse_stability = lapsed.stability as f64,
+        relearn_minutes = relearn_steps_minutes.iter().sum::<f64>(),
+        interval = next_interval(&fsrs, Some(memory.stability as f64), desired_retention, max_interval, true, 0)
+    ))
+}
+
+/// Projects a card's schedule when every review gets `rating` on its due day.
+#[extendr]
+fn fsrs_interval_sequence(
+    stability: f64,
+    difficulty: f64,
+    rating: i32,
+    desired_retention: f64,
+    n_reviews: i32,
+    params: Option<Vec<f64>>,
+    precision: &str
+) -> Result<List> {
+    check_precision(precision)?;
+    let r = (rating as u32).min(4).max(1);
+    let n = n_reviews.max(0) as usize;
+    
+    // (interval, stability, difficulty) after each projected review
+    let mut rows: Vec<(f64, f64, f64)> = Vec::with_capacity(n);
+    if precision == "f64" {
+        let w = filled_params_f64(params)?;
+        let mut state = (stability, difficulty);
+        for _ in 0..n {
+            let interval = interval_f64(state.0, desired_retention, w[20]).round().max(1.0);
+            state = review_state_f64(&w, Some(state), r, interval as u32, true);
+            rows.push((interval, state.0, state.1));
+        }
+    } else {
+        let fsrs = create_fsrs(params)?;
+        let retention = desired_retention as f32;
+        let mut state = MemoryState {
+            stability: stability as f32,
+            difficulty: difficulty as f32
+        };
+        for _ in 0..n {
+            let interval = fsrs.next_interval(Some(state.stability), retention, 0)
+                .round()
+                .max(1.0);
+            let states = next_states(&fsrs, Some(state), retention, interval as u32)?;
+            state = memory_for_rating(&states, r);
+            rows.push((interval as f64, state.stability as f64, clamp_difficulty(state.difficulty)));
+        }
+    }
+    
+    let mut day = 0.0;
+    let days: Vec<f64> = rows.iter().map(|row| { day += row.0; day }).collect();
+    Ok(list!(
+        day = days,
+        interval = rows.iter().map(|row| row.0).collect::<Vec<f64>>(),
+        stability = rows.iter().map(|row| row.1).collect::<Vec<f64>>(),
+        difficulty = rows.iter().map(|row| row.2).collect::<Vec<f64>>()
+    ))
+}
+
+/// Reviews a new card with each of `ratings` in turn, the first on day 0
+/// and each later one on the day the previous review scheduled (interval
+/// rounded to whole days, at least 1), so the elapsed days come from the
+/// schedule itself.
+#[extendr]
+fn fsrs_default_ladder(
+    ratings: Vec<i32>,
+    desired_retention: f64,
+    params: Option<Vec<f64>>
+) -> Result<List> {
+    let fsrs = create_fsrs(params)?;
+    let retention = desired_retention as f32;
+    let n = ratings.len();
+    
+    let mut day = Vec::with_capacity(n);
+    let mut elapsed_days = Vec::with_capacity(n);
+    let mut stability = Vec::with_capacity(n);
+    let mut difficulty = Vec::with_capacity(n);
+    let mut interval = Vec::with_capacity(n);
+    let (mut state, mut today, mut elapsed) = (None, 0.0, 0.0);
+    for &rating in &ratings {
+        let r = (rating as u32).min(4).max(1);
+        let states = next_states(&fsrs, state, retention, elapsed as u32)?;
+        let memory = memory_for_rating(&states, r);
+        let next = fsrs.next_interval(Some(memory.stability), retention, 0)
+            .round()
+            .max(1.0) as f64;
+        day.push(today);
+        elapsed_days.push(elapsed);
+        stability.push(memory.stability as f64);
+        difficulty.push(clamp_difficulty(memory.difficulty));
+        interval.push(next);
+        state = Some(memory);
+        elapsed = next;
+        today += next;
+    }
+    
+    Ok(list!(
+        day = day,
+        elapsed_days = elapsed_days,
+        stability = stability,
+        difficulty = difficulty,
+        interval = interval
+    ))
+}
+
+#[extendr]
+fn fsrs_difficulty_components(difficulty: f64, rating: i32, params: Option<Vec<f64>>) -> Result<List> {
+    let w: Vec<f64> = filled_params(&valid_params(params)?).iter().map(|&x| x as f64).collect();
+    let g = (rating as u32).min(4).max(1) as f64;
+    
+    // Initial difficulty of a card first rated Easy: the mean-reversion anchor
+    let anchor = w[4] - (w[5] * 3.0).exp() + 1.0;
+    let delta = -w[6] * (g - 3.0);
+    // Linear damping: steps shrink as difficulty approaches 10
+    let damped_delta = delta * (10.0 - difficulty) / 9.0;
+    let damped = difficulty + damped_delta;
+    let mean_reversion = w[7] * (anchor - damped);
+    
+    Ok(list!(
+        delta = delta,
+        damped_delta = damped_delta,
+        mean_reversion = mean_reversion,
+        anchor = anchor,
+        difficulty = (damped + mean_reversion).clamp(1.0, 10.0)
+    ))
 }
 
 #[extendr]
-fn fsrs_retrievability(stability: f64, elapsed_days: f64) -> f64 {
+fn fsrs_retrievability(stability: f64, elapsed_days: f64, params: Option<Vec<f64>>) -> f64 {
     if stability <= 0.0 {
         return 1.0;
     }
-    (1.0 + FACTOR * elapsed_days / stability).powf(DECAY)
+    forgetting_curve(stability, elapsed_days, decay_from(&params_f32(params)))
 }
 
 #[extendr]
-fn fsrs_retrievability_vec(stability: Vec<f64>, elapsed_days: Vec<f64>) -> Vec<f64> {
+fn fsrs_retrievability_vec(
+    stability: Vec<f64>,
+    elapsed_days: Vec<f64>,
+    params: Option<Vec<f64>>
+) -> Vec<f64> {
+    let decay = decay_from(&params_f32(params));
     stability.iter()
         .zip(elapsed_days.iter())
         .map(|(s, t)| {
-            if *s <= 0.0 {
+            if s.is_na() || t.is_na() {
+                f64::na()
+            } else if *s <= 0.0 {
                 1.0
             } else {
-                (1.0 + FACTOR * t / s).powf(DECAY)
+                forgetting_curve(*s, *t, decay)
             }
         })
         .collect()
 }
 
-// ============================================================================
-// SM-2 MIGRATION
-// ============================================================================
+/// Whole days from each review to `now` as Anki counts them: days start at
+/// `rollover_hour` local time and are numbered in the current UTC offset,
+/// so earlier days keep today's boundary across a DST change. NA for an NA
+/// review or one on a later day than `now`.
+#[extendr]
+fn fsrs_elapsed_days(
+    last_review_epoch_s: Vec<f64>,
+    now_epoch_s: f64,
+    rollover_hour: f64,
+    timezone_offset_s: f64
+) -> Vec<i32> {
+    let day = |t: f64| ((t + timezone_offset_s - rollover_hour * 3600.0) / 86400.0).floor();
+    let today = day(now_epoch_s);
+    last_review_epoch_s.iter()
+        .map(|&t| {
+            if t.is_nan() || day(t) > today {
+                i32::na()
+            } else {
+                (today - day(t)) as i32
+            }
+        })
+        .collect()
+}
 
+/// Current recall probability of every card in a deck, NA for cards without
+/// a usable memory state (NA inputs or non-positive stability).
 #[extendr]
-fn fsrs_from_sm2(
-    ease_factor: f64, 
-    interval: f64, 
-    sm2_retention: f64,
+fn fsrs_deck_retrievability(
+    stability: Vec<f64>,
+    last_review_days_ago: Vec<f64>,
     params: Option<Vec<f64>>
-) -> List {
-    let fsrs = create_fsrs(params);
-    let state = fsrs.memory_state_from_sm2(
-        ease_factor as f32,
-        interval as f32, 
-        sm2_retention as f32
-    ).unwrap();
+) -> Vec<f64> {
+    let decay = decay_from(&params_f32(params));
+    stability.iter()
+        .zip(last_review_days_ago.iter())
+        .map(|(&s, &t)| {
+            if s.is_na() || t.is_na() || s <= 0.0 {
+                f64::na()
+            } else {
+                forgetting_curve(s, t, decay)
+            }
+        })
+        .collect()
+}
+
+/// Counts of difficulties in `n_bins` equal-width bins over 1-10, with the
+/// mean. NA values are counted separately; others are clamped to 1-10 as
+/// the model does, so 10 falls in the last bin.
+#[extendr]
+fn fsrs_difficulty_histogram(difficulty: Vec<f64>, n_bins: i32) -> List {
+    let n_bins = n_bins.max(1) as usize;
+    let width = 9.0 / n_bins as f64;
+    let mut count = vec![0i32; n_bins];
+    let mut sum = 0.0;
+    let mut n = 0;
+    for &d in &difficulty {
+        if d.is_nan() { continue; }
+        let d = d.clamp(1.0, 10.0);
+        count[(((d - 1.0) / width) as usize).min(n_bins - 1)] += 1;
+        sum += d;
+        n += 1;
+    }
+    let center: Vec<f64> = (0..n_bins).map(|i| 1.0 + width * (i as f64 + 0.5)).collect();
     
     list!(
-        stability = state.stability as f64,
-        difficulty = state.difficulty as f64
+        center = center,
+        count = count,
+        mean = if n > 0 { sum / n as f64 } else { f64::na() },
+        n_missing = (difficulty.len() - n) as i32
     )
 }
 
-// ============================================================================
-// REVIEW HISTORY PROCESSING
-// ============================================================================
+/// Retrievability of every card on its due date, `interval` days after its
+/// last review, NA where either input is NA or not positive.
+#[extendr]
+fn fsrs_retention_at_interval(
+    stability: Vec<f64>,
+    interval: Vec<f64>,
+    params: Option<Vec<f64>>
+) -> Vec<f64> {
+    let decay = decay_from(&params_f32(params));
+    stability.iter()
+        .zip(interval.iter())
+        .map(|(&s, &t)| {
+            if s.is_na() || t.is_na() || s <= 0.0 || t <= 0.0 {
+                f64::na()
+            } else {
+                forgetting_curve(s, t, decay)
+            }
+        })
+        .collect()
+}
 
 #[extendr]
-fn fsrs_memory_state(
-    ratings: Vec<i32>,
-    delta_ts: Vec<i32>,
-    initial_stability: Option<f64>,
-    initial_difficulty: Option<f64>,
+fn fsrs_forgetting_curve(
+    elapsed_days: Vec<f64>,
+    stability: f64,
     params: Option<Vec<f64>>
-) -> List {
-    let fsrs = create_fsrs(params);
-    
-    let reviews: Vec<FSRSReview> = ratings.iter()
-        .zip(delta_ts.iter())
-        .map(|(&r, &t)| FSRSReview { 
-            rating: (r as u32).min(4).max(1), 
-            delta_t: t as u32 
+) -> Vec<f64> {
+    let decay = decay_from(&params_f32(params));
+    elapsed_days.iter()
+        .map(|&t| {
+            if stability <= 0.0 || t <= 0.0 {
+                1.0
+            } else {
+                forgetting_curve(stability, t, decay)
+            }
         })
-        .collect();
-    
-    let item = FSRSItem { reviews };
-    
-    let initial = match (initial_stability, initial_difficulty) {
-        (Some(s), Some(d)) => Some(MemoryState {
-            stability: s as f32,
-            difficulty: d as f32,
-        }),
-        _ => None,
-    };
-    
-    let state = fsrs.memory_state(item, initial).unwrap();
-    
-    list!(
-        stability = state.stability as f64,
-        difficulty = state.difficulty as f64
-    )
+        .collect()
 }
 
-// ============================================================================
-// PARAMETER OPTIMIZATION
-// ============================================================================
+/// The forgetting-curve constant for each (negative) `decay` exponent.
+#[extendr]
+fn fsrs_factor_from_decay(decay: Vec<f64>) -> Vec<f64> {
+    decay.iter()
+        .map(|&d| if d.is_na() { f64::na() } else { factor_from_decay(d) })
+        .collect()
+}
 
+/// Inverse of the interval formula: the stability for which `interval` days
+/// is the scheduled interval at `desired_retention`.
 #[extendr]
-fn fsrs_optimize(
-    ratings: Vec<i32>,
-    delta_ts: Vec<i32>,
+fn fsrs_stability_for_interval(
+    interval: Vec<f64>,
+    desired_retention: f64,
+    params: Option<Vec<f64>>
+) -> Vec<f64> {
+    let decay = decay_from(&params_f32(params));
+    let factor = factor_from_decay(-decay);
+    let scale = desired_retention.powf(-1.0 / decay) - 1.0;
+    interval.iter()
+        .map(|&t| if t.is_na() { f64::na() } else { t * factor / scale })
+        .collect()
+}
+
+/// Days until retrievability falls to 0.5: the interval formula at a
+/// desired retention of 0.5, which is linear in stability.
+#[extendr]
+fn fsrs_half_life(stability: Vec<f64>, params: Option<Vec<f64>>) -> Vec<f64> {
+    let decay = decay_from(&params_f32(params));
+    stability.iter()
+        .map(|&s| if s.is_na() { f64::na() } else { interval_f64(s, 0.5, decay) })
+        .collect()
+}
+
+/// An `FSRS` built once and kept on the Rust side between calls, so an app
+/// scheduling many cards with the same parameters skips `FSRS::new` (and
+/// the parameter round trip from R) on every call.
+/// `fsrs` is None once `fsrs_model_free` has released it ahead of garbage
+/// collection.
+#[derive(Debug)]
+struct FsrsModel {
+    fsrs: Option<FSRS>,
+    params: Vec<f32>,
+}
+
+#[extendr]
+fn fsrs_model_new(params: Option<Vec<f64>>) -> Result<ExternalPtr<FsrsModel>> {
+    let params = valid_params(params)?;
+    let fsrs = fsrs_from_weights(&params)?;
+    // R owns the pointer; extendr's finalizer drops the model on collection
+    Ok(ExternalPtr::new(FsrsModel { fsrs: Some(fsrs), params }))
+}
+
+/// The model behind `handle`, or an error if it was saved and reloaded,
+/// which leaves R holding a null pointer.
+fn model_ref(handle: &ExternalPtr<FsrsModel>) -> Result<&FsrsModel> {
+    handle.try_addr().map_err(|_| Error::Other(
+        "model handle is no longer valid (it does not survive saveRDS() or a new session); rebuild it with fsrs_model()".to_string()
+    ))
+}
+
+fn model_fsrs(handle: &ExternalPtr<FsrsModel>) -> Result<&FSRS> {
+    model_ref(handle)?.fsrs.as_ref()
+        .ok_or_else(|| Error::Other("model was released by fsrs_free()".to_string()))
+}
+
+#[extendr]
+fn fsrs_model_params(model: ExternalPtr<FsrsModel>) -> Result<Robj> {
+    Ok(named_params(&model_ref(&model)?.params))
+}
+
+/// Drops the model now rather than when R collects the handle. The handle
+/// itself stays valid as an R object but can no longer schedule.
+#[extendr]
+fn fsrs_model_free(mut model: ExternalPtr<FsrsModel>) {
+    // A null (reloaded) handle has nothing to release
+    if let Ok(inner) = model.try_addr_mut() {
+        inner.fsrs = None;
+    }
+}
+
+#[extendr]
+fn fsrs_model_initial_state(
+    model: ExternalPtr<FsrsModel>,
+    rating: i32,
+    short_term: bool,
+    difficulty_override: Option<f64>
+) -> Result<List> {
+    initial_state(model_fsrs(&model)?, rating, short_term, difficulty_override)
+}
+
+#[extendr]
+fn fsrs_model_next_interval(
+    model: ExternalPtr<FsrsModel>,
+    stability: Option<f64>,
+    desired_retention: f64,
+    max_interval: f64,
+    round: bool,
+    rating: i32
+) -> Result<f64> {
+    check_retention("desired_retention", desired_retention)?;
+    let fsrs = model_fsrs(&model)?;
+    Ok(next_interval(fsrs, stability, desired_retention, max_interval, round, rating))
+}
+
+#[extendr]
+fn fsrs_model_next_state(
+    model: ExternalPtr<FsrsModel>,
+    stability: f64,
+    difficulty: f64,
+    elapsed_days: f64,
+    rating: i32,
+    enable_short_term: bool
+) -> Result<List> {
+    next_state(model_fsrs(&model)?, stability, difficulty, elapsed_days, rating, enable_short_term)
+}
+
+#[extendr]
+fn fsrs_model_repeat(
+    model: ExternalPtr<FsrsModel>,
+    stability: Option<f64>,
+    difficulty: Option<f64>,
+    elapsed_days: f64,
+    retention_for_scheduling: f64,
+    retention_for_interval: f64,
+    enable_short_term: bool
+) -> Result<List> {
+    repeat(
+        model_fsrs(&model)?, stability, difficulty, elapsed_days,
+        retention_for_scheduling, retention_for_interval, enable_short_term
+    )
+}
+
+// ============================================================================
+// SM-2 MIGRATION
+// ============================================================================
+
+#[extendr]
+fn fsrs_from_sm2(
+    ease_factor: f64, 
+    interval: f64, 
+    sm2_retention: f64,
+    desired_retention: f64,
+    params: Option<Vec<f64>>
+) -> Result<List> {
+    let fsrs = create_fsrs(params)?;
+    let state = memory_state_from_sm2(&fsrs, ease_factor, interval, sm2_retention)?;
+    
+    Ok(list!(
+        stability = state.stability as f64,
+        difficulty = clamp_difficulty(state.difficulty),
+        interval = migrated_interval(&fsrs, &state, desired_retention)
+    ))
+}
+
+#[extendr]
+fn fsrs_from_sm2_vec(
+    ease_factor: Vec<f64>,
+    interval: Vec<f64>,
+    sm2_retention: Vec<f64>,
+    desired_retention: f64,
+    params: Option<Vec<f64>>,
+    difficulty_override: Option<Vec<f64>>
+) -> Result<List> {
+    let n = ease_factor.len();
+    let recyclable = |len: usize| len == 1 || len == n;
+    if interval.len() != n
+        || !recyclable(sm2_retention.len())
+        || !difficulty_override.as_ref().map_or(true, |d| recyclable(d.len())) {
+        return Err(Error::Other(format!(
+            "interval must have the length of ease_factor ({}), and sm2_retention and difficulty_override length 1 or {} (interval {}, sm2_retention {}, difficulty_override {})",
+            n, n, interval.len(), sm2_retention.len(), difficulty_override.as_ref().map_or(0, |d| d.len())
+        )));
+    }
+    let fsrs = create_fsrs(params)?;
+    let mut stability = Vec::with_capacity(n);
+    let mut difficulty = Vec::with_capacity(n);
+    let mut next_interval = Vec::with_capacity(n);
+    
+    for i in 0..n {
+        // A length-1 sm2_retention is recycled across all cards
+        let retention = if sm2_retention.len() == 1 { sm2_retention[0] } else { sm2_retention[i] };
+        let inputs_ok = !ease_factor[i].is_na() && !interval[i].is_na() && !retention.is_na();
+        // A card that fails conversion gets NA instead of aborting the batch
+        match inputs_ok.then(|| fsrs.memory_state_from_sm2(
+            ease_factor[i] as f32,
+            interval[i] as f32,
+            retention as f32
+        )) {
+            Some(Ok(state)) => {
+                // Like sm2_retention, a length-1 override is recycled
+                let d = difficulty_override.as_ref()
+                    .map(|d| if d.len() == 1 { d[0] } else { d[i] })
+                    .filter(|d| !d.is_na())
+                    .map_or(state.difficulty, |d| d as f32);
+                stability.push(state.stability as f64);
+                difficulty.push(clamp_difficulty(d));
+                next_interval.push(migrated_interval(&fsrs, &state, desired_retention));
+            },
+            _ => {
+                stability.push(f64::na());
+                difficulty.push(f64::na());
+                next_interval.push(f64::na());
+            }
+        }
+    }
+    
+    Ok(list!(
+        stability = stability,
+        difficulty = difficulty,
+        interval = next_interval
+    ))
+}
+
+/// Interval after a Good review on the due date divided by the interval
+/// before it, a descriptive analogue of an SM-2 ease factor. Both intervals
+/// are unrounded; the review happens on the rounded due day. NA where
+/// stability or difficulty is NA or stability is not positive.
+#[extendr]
+fn fsrs_effective_ease(
+    stability: Vec<f64>,
+    difficulty: Vec<f64>,
+    desired_retention: f64,
+    params: Option<Vec<f64>>
+) -> Result<Vec<f64>> {
+    check_retention("desired_retention", desired_retention)?;
+    let decay = decay_from(&params_f32(params.clone()));
+    let fsrs = create_fsrs(params)?;
+    stability.iter()
+        .enumerate()
+        .map(|(i, &s)| {
+            // A length-1 difficulty is recycled across all cards
+            let d = if difficulty.len() == 1 { difficulty[0] } else { difficulty[i] };
+            if s.is_na() || d.is_na() || s <= 0.0 {
+                return Ok(f64::na());
+            }
+            let interval = interval_f64(s, desired_retention, decay);
+            let state = MemoryState { stability: s as f32, difficulty: d as f32 };
+            let good = memory_states(&fsrs, Some(state), interval.round().max(1.0) as u32)?.good.memory;
+            Ok(interval_f64(good.stability as f64, desired_retention, decay) / interval)
+        })
+        .collect()
+}
+
+/// Whole-day FSRS interval (at least 1) for a migrated card's stability.
+fn migrated_interval(fsrs: &FSRS, state: &MemoryState, desired_retention: f64) -> f64 {
+    let interval = fsrs.next_interval(Some(state.stability), desired_retention as f32, 0);
+    (interval as f64).round().max(1.0)
+}
+
+// ============================================================================
+// REVIEW HISTORY PROCESSING
+// ============================================================================
+
+/// With no reviews the initial state is returned as is; with neither there
+/// is no state, which is an error. A lone review without an initial state
+/// gives the new-card state for its rating, whatever its `delta_t`.
+#[extendr]
+fn fsrs_memory_state(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    initial_stability: Option<f64>,
+    initial_difficulty: Option<f64>,
+    params: Option<Vec<f64>>,
+    min_stability: f64,
+    enable_short_term: bool
+) -> Result<List> {
+    let fsrs = create_fsrs(params)?;
+    let state = match (initial_stability, initial_difficulty) {
+        (Some(s), Some(d)) => Some(MemoryState {
+            stability: s as f32,
+            difficulty: d as f32,
+        }),
+        _ => None,
+    };
+    let (state, n_clamped) = replay_reviews(
+        &fsrs, state, &ratings, &delta_ts, min_stability as f32, enable_short_term
+    )?;
+    let Some(state) = state else {
+        return Err(Error::Other(
+            "no reviews to replay: ratings is empty and no initial state was given".to_string()
+        ));
+    };
+    
+    Ok(list!(
+        stability = state.stability as f64,
+        difficulty = clamp_difficulty(state.difficulty),
+        n_clamped = n_clamped
+    ))
+}
+
+/// `fsrs_memory_state` for a new card over only its first `k` reviews, so an
+/// app can roll a card back (e.g. on undo) without copying its history.
+/// With `k` 0 nothing is replayed and the new-card state of `Card`
+/// (stability and difficulty 0) is returned.
+#[extendr]
+fn fsrs_replay_prefix(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    k: i32,
+    params: Option<Vec<f64>>,
+    min_stability: f64,
+    enable_short_term: bool
+) -> Result<List> {
+    let fsrs = create_fsrs(params)?;
+    let k = (k.max(0) as usize).min(ratings.len()).min(delta_ts.len());
+    let (state, n_clamped) = replay_reviews(
+        &fsrs, None, &ratings[..k], &delta_ts[..k], min_stability as f32, enable_short_term
+    )?;
+    let (stability, difficulty) = state.map_or((0.0, 0.0), |state| {
+        (state.stability as f64, clamp_difficulty(state.difficulty))
+    });
+    
+    Ok(list!(
+        stability = stability,
+        difficulty = difficulty,
+        n_clamped = n_clamped
+    ))
+}
+
+/// Seeds the memory state from a card's SM-2 ease and interval, then replays
+/// the reviews it has had since switching to FSRS from that state.
+#[extendr]
+fn fsrs_from_sm2_then_replay(
+    ease_factor: f64,
+    interval: f64,
+    sm2_retention: f64,
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    params: Option<Vec<f64>>,
+    min_stability: f64,
+    enable_short_term: bool
+) -> Result<List> {
+    let fsrs = create_fsrs(params)?;
+    let seed = memory_state_from_sm2(&fsrs, ease_factor, interval, sm2_retention)?;
+    let (state, n_clamped) = replay_reviews(
+        &fsrs, Some(seed), &ratings, &delta_ts, min_stability as f32, enable_short_term
+    )?;
+    // Seeded, so there is always a state even without reviews
+    let state = state.unwrap_or(seed);
+    Ok(list!(
+        stability = state.stability as f64,
+        difficulty = clamp_difficulty(state.difficulty),
+        n_clamped = n_clamped
+    ))
+}
+
+#[extendr]
+fn fsrs_memory_state_batch(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
     card_starts: Vec<i32>,
+    params: Option<Vec<f64>>,
     enable_short_term: bool
+) -> Result<List> {
+    let fsrs = create_fsrs(params)?;
+    let windows = review_windows(&ratings, &delta_ts, &card_starts);
+    let mut stability = Vec::with_capacity(windows.len());
+    let mut difficulty = Vec::with_capacity(windows.len());
+    
+    for window in windows {
+        match card_final_state(&fsrs, &ratings, &delta_ts, window, enable_short_term) {
+            Some(state) => {
+                stability.push(state.stability as f64);
+                difficulty.push(clamp_difficulty(state.difficulty));
+            },
+            None => {
+                stability.push(f64::na());
+                difficulty.push(f64::na());
+            }
+        }
+    }
+    
+    Ok(list!(
+        stability = stability,
+        difficulty = difficulty
+    ))
+}
+
+/// Final memory state of every card, as in `fsrs_memory_state_batch`, and
+/// the interval each would be scheduled from its last review today.
+#[extendr]
+fn fsrs_reschedule(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    card_starts: Vec<i32>,
+    desired_retention: f64,
+    params: Option<Vec<f64>>,
+    max_interval: f64,
+    enable_short_term: bool
+) -> Result<List> {
+    check_retention("desired_retention", desired_retention)?;
+    let fsrs = create_fsrs(params)?;
+    let windows = review_windows(&ratings, &delta_ts, &card_starts);
+    let mut stability = Vec::with_capacity(windows.len());
+    let mut difficulty = Vec::with_capacity(windows.len());
+    let mut interval = Vec::with_capacity(windows.len());
+    
+    for window in windows {
+        match card_final_state(&fsrs, &ratings, &delta_ts, window, enable_short_term) {
+            Some(state) => {
+                let s = state.stability as f64;
+                stability.push(s);
+                difficulty.push(clamp_difficulty(state.difficulty));
+                interval.push(next_interval(&fsrs, Some(s), desired_retention, max_interval, true, 0));
+            },
+            None => {
+                stability.push(f64::na());
+                difficulty.push(f64::na());
+                interval.push(f64::na());
+            }
+        }
+    }
+    
+    Ok(list!(
+        stability = stability,
+        difficulty = difficulty,
+        interval = interval
+    ))
+}
+
+/// Replays the card whose reviews occupy `window` from scratch. A card that
+/// cannot be replayed gives None, so batch callers report NA for it instead
+/// of aborting.
+fn card_final_state(
+    fsrs: &FSRS,
+    ratings: &[i32],
+    delta_ts: &[i32],
+    window: Option<(usize, usize)>,
+    enable_short_term: bool
+) -> Option<MemoryState> {
+    window.and_then(|(start, end)| {
+        let mut reviews = card_review_slice(ratings, delta_ts, start, end);
+        if !enable_short_term {
+            // Dropping same-day reviews leaves the state where they found it
+            let mut first = true;
+            reviews.retain(|r| std::mem::take(&mut first) || r.delta_t > 0);
+        }
+        fsrs.memory_state(FSRSItem { reviews }, None).ok()
+    })
+}
+
+#[extendr]
+fn fsrs_memory_state_trajectory(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    initial_stability: Option<f64>,
+    initial_difficulty: Option<f64>,
+    params: Option<Vec<f64>>,
+    enable_short_term: bool,
+    precision: &str
+) -> Result<List> {
+    check_precision(precision)?;
+    let initial = match (initial_stability, initial_difficulty) {
+        (Some(s), Some(d)) => Some((s, d)),
+        _ => None,
+    };
+    
+    let mut stability = Vec::with_capacity(ratings.len());
+    let mut difficulty = Vec::with_capacity(ratings.len());
+    let mut retrievability = Vec::with_capacity(ratings.len());
+    
+    if precision == "f64" {
+        let w = filled_params_f64(params)?;
+        let mut state = initial;
+        for (&r, &t) in ratings.iter().zip(delta_ts.iter()) {
+            let days = if state.is_some() { t.max(0) as u32 } else { 0 };
+            retrievability.push(match state {
+                Some(prev) => forgetting_curve(prev.0, days as f64, w[20]),
+                None => 1.0,
+            });
+            let next = review_state_f64(&w, state, (r as u32).min(4).max(1), days, enable_short_term);
+            stability.push(next.0);
+            difficulty.push(next.1);
+            state = Some(next);
+        }
+    } else {
+        let decay = decay_from(&params_f32(params.clone()));
+        let fsrs = create_fsrs(params)?;
+        let mut state = initial.map(|(s, d)| MemoryState {
+            stability: s as f32,
+            difficulty: d as f32,
+        });
+        for (&r, &t) in ratings.iter().zip(delta_ts.iter()) {
+            let days = if state.is_some() { t.max(0) as u32 } else { 0 };
+            // Model prediction at the moment of review, before the rating is known
+            retrievability.push(match state {
+                Some(prev) => forgetting_curve(prev.stability as f64, days as f64, decay),
+                None => 1.0,
+            });
+            let next = review_state(&fsrs, state, (r as u32).min(4).max(1), days, enable_short_term)?;
+            stability.push(next.stability as f64);
+            difficulty.push(clamp_difficulty(next.difficulty));
+            state = Some(next);
+        }
+    }
+    
+    Ok(list!(
+        stability = stability,
+        difficulty = difficulty,
+        retrievability = retrievability
+    ))
+}
+
+// ============================================================================
+// PARAMETER OPTIMIZATION
+// ============================================================================
+
+#[extendr]
+fn fsrs_card_starts(card_ids: Vec<f64>) -> Result<Vec<i32>> {
+    let mut starts: Vec<i32> = Vec::new();
+    let mut seen: HashSet<u64> = HashSet::new();
+    for (i, &id) in card_ids.iter().enumerate() {
+        if i > 0 && card_ids[i - 1] == id {
+            continue;
+        }
+        // A new run must be a card not seen before, or its reviews are split
+        if !seen.insert(id.to_bits()) {
+            return Err(Error::Other(format!(
+                "card ids must be grouped contiguously; the card at position {} already appeared earlier",
+                i + 1
+            )));
+        }
+        starts.push(i as i32 + 1);
+    }
+    Ok(starts)
+}
+
+/// Per-review `delta_t` from day numbers grouped by card: the number of day
+/// boundaries crossed since the card's previous review, 0 for its first.
+#[extendr]
+fn fsrs_delta_days(days: Vec<f64>, card_starts: Vec<i32>) -> Result<Vec<i32>> {
+    let mut delta_ts = vec![0; days.len()];
+    for (start, end) in card_windows(days.len(), &card_starts).into_iter().flatten() {
+        for i in start + 1..end {
+            let delta = days[i].floor() - days[i - 1].floor();
+            if delta < 0.0 {
+                return Err(Error::Other(format!(
+                    "days must not decrease within a card; review {} is earlier than the one before it",
+                    i + 1
+                )));
+            }
+            delta_ts[i] = delta as i32;
+        }
+    }
+    Ok(delta_ts)
+}
+
+#[extendr]
+fn fsrs_build_items(
+    cid: Vec<f64>,
+    ratings: Vec<i32>,
+    timestamps_ms: Vec<f64>,
+    day_cutoff_hour: f64,
+    same_day: &str
+) -> Result<List> {
+    if !matches!(same_day, "first" | "last" | "keep") {
+        return Err(Error::Other(format!(
+            "same_day must be \"first\", \"last\" or \"keep\", not \"{}\"", same_day
+        )));
+    }
+    
+    // Anki card ids are millisecond timestamps, too large for an R integer,
+    // so they arrive as doubles
+    let mut order: Vec<usize> = (0..cid.len()).collect();
+    order.sort_by(|&a, &b| {
+        cid[a].total_cmp(&cid[b]).then(timestamps_ms[a].total_cmp(&timestamps_ms[b]))
+    });
+    
+    // Day number of a review, with days starting at `day_cutoff_hour`
+    let day = |i: usize| ((timestamps_ms[i] / 3_600_000.0 - day_cutoff_hour) / 24.0).floor() as i64;
+    let same_card_day = |a: usize, b: usize| cid[a] == cid[b] && day(a) == day(b);
+    
+    // Drop all but one review per card and day if asked to
+    let kept: Vec<usize> = (0..order.len())
+        .filter(|&pos| match same_day {
+            "first" => pos == 0 || !same_card_day(order[pos - 1], order[pos]),
+            "last" => pos + 1 == order.len() || !same_card_day(order[pos], order[pos + 1]),
+            _ => true,
+        })
+        .map(|pos| order[pos])
+        .collect();
+    
+    let mut out_cid = Vec::with_capacity(kept.len());
+    let mut out_ratings = Vec::with_capacity(kept.len());
+    let mut delta_ts = Vec::with_capacity(kept.len());
+    let mut card_starts = Vec::new();
+    
+    for (pos, &i) in kept.iter().enumerate() {
+        let first = pos == 0 || cid[kept[pos - 1]] != cid[i];
+        if first {
+            card_starts.push(pos as i32 + 1);
+            delta_ts.push(0);
+        } else {
+            delta_ts.push((day(i) - day(kept[pos - 1])) as i32);
+        }
+        out_cid.push(cid[i]);
+        out_ratings.push(ratings[i]);
+    }
+    
+    Ok(list!(
+        cid = out_cid,
+        ratings = out_ratings,
+        delta_ts = delta_ts,
+        card_starts = card_starts
+    ))
+}
+
+/// The (first rating, first long-term `delta_t`) groups fsrs-rs'
+/// `filter_outlier` drops, given one such key per card.
+fn outlier_groups<'a>(keys: impl IntoIterator<Item = &'a (i32, i32)>) -> HashSet<(i32, i32)> {
+    let mut groups: BTreeMap<i32, BTreeMap<i32, usize>> = BTreeMap::new();
+    for &(rating, delta_t) in keys {
+        *groups.entry(rating).or_default().entry(delta_t).or_default() += 1;
+    }
+    
+    let mut removed: HashSet<(i32, i32)> = HashSet::new();
+    for (&rating, delta_t_groups) in &groups {
+        // Largest groups first, ties by longer delta_t; removal runs from the end
+        let mut sub_groups: Vec<(i32, usize)> = delta_t_groups.iter().map(|(&d, &n)| (d, n)).collect();
+        sub_groups.sort_by(|a, b| b.1.cmp(&a.1).then(b.0.cmp(&a.0)));
+        let total: usize = sub_groups.iter().map(|g| g.1).sum();
+        let max_delta_t_kept = if rating != 4 { 100 } else { 365 };
+        let mut n_removed = 0;
+        for &(delta_t, n) in sub_groups.iter().rev() {
+            if n_removed + n >= 20.max(total / 20) {
+                if n < 6 || delta_t > max_delta_t_kept {
+                    removed.insert((rating, delta_t));
+                }
+            } else {
+                n_removed += n;
+                removed.insert((rating, delta_t));
+            }
+        }
+    }
+    removed
+}
+
+/// Flags the reviews of cards fsrs-rs drops as outliers before training,
+/// mirroring its private `filter_outlier`. Cards are grouped by first rating
+/// and the `delta_t` of their first review on a later day. Within each first
+/// rating the smallest groups are dropped until 5% of the cards (at least
+/// 20) are, as are groups with fewer than 6 cards or a `delta_t` above 100
+/// days (365 after a first Easy). Reviews above `max_delta_t` are flagged too.
+#[extendr]
+fn fsrs_flag_outliers(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    card_starts: Vec<i32>,
+    max_delta_t: Option<i32>
 ) -> List {
+    let windows = review_windows(&ratings, &delta_ts, &card_starts);
+    let keys: Vec<Option<(i32, i32)>> = windows.iter()
+        .map(|w| w.and_then(|(start, end)| {
+            (start + 1..end)
+                .find(|&i| delta_ts[i] > 0)
+                .map(|i| (ratings[start].clamp(1, 4), delta_ts[i]))
+        }))
+        .collect();
+    
+    let removed = outlier_groups(keys.iter().flatten());
+    
+    let mut outlier = vec![false; ratings.len()];
+    let mut n_cards = 0;
+    for (window, key) in windows.iter().zip(keys.iter()) {
+        if let (Some((start, end)), Some(key)) = (window, key) {
+            if removed.contains(key) {
+                outlier[*start..*end].fill(true);
+                n_cards += 1;
+            }
+        }
+    }
+    if let Some(max_delta_t) = max_delta_t {
+        for (o, &t) in outlier.iter_mut().zip(delta_ts.iter()) {
+            *o |= t > max_delta_t;
+        }
+    }
+    
+    list!(outlier = outlier, n_outlier_cards = n_cards)
+}
+
+#[extendr]
+fn fsrs_optimize(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    card_starts: Vec<i32>,
+    enable_short_term: bool,
+    min_items: i32,
+    progress: Option<Function>,
+    max_items: Option<i32>,
+    seed: i32,
+    review_ages: Option<Vec<f64>>,
+    max_age_days: Option<f64>,
+    n_threads: i32
+) -> Result<List> {
+    let pool = thread_pool(n_threads)?;
+    let windows = review_windows(&ratings, &delta_ts, &card_starts);
+    let counts = card_item_counts(&windows, &delta_ts);
+    let (n_items_total, n_cards, n_skipped) = item_totals(&counts);
+    
+    // Items are dropped, not down-weighted, by age: ComputeParametersInput
+    // takes no per-item weights
+    let is_recent = |i: usize| match (&review_ages, max_age_days) {
+        (Some(ages), Some(max_age)) => ages.get(i).map_or(true, |&age| age <= max_age),
+        _ => true,
+    };
+    let recent_counts: Vec<usize> = windows.iter()
+        .map(|&w| w.map_or(0, |(start, end)| window_item_count_where(&delta_ts, start, end, is_recent)))
+        .collect();
+    let n_recent = recent_counts.iter().sum::<usize>() as i32;
+    
+    // Under a budget only the sampled cards' items are ever built
+    let keep = match max_items {
+        Some(m) if m < n_recent => sample_cards(&recent_counts, m.max(0) as usize, seed),
+        _ => vec![true; counts.len()],
+    };
+    // Rebuilt for the fitted log loss rather than cloned, so no second copy
+    // of the items is held while training
+    let kept_items = || -> Vec<FSRSItem> {
+        windows.iter()
+            .zip(keep.iter())
+            .filter(|&(_, &k)| k)
+            .filter_map(|(&w, _)| w)
+            .flat_map(|(start, end)| window_items_where(&ratings, &delta_ts, start, end, is_recent))
+            .collect()
+    };
+    let items = kept_items();
+    
+    if items.is_empty() {
+        return Ok(list!(
+            parameters = Vec::<f64>::new(),
+            success = false,
+            error = error_list("no_data", "No valid review data provided"),
+            n_items = 0,
+            n_items_total = n_items_total,
+            n_cards = n_cards,
+            n_skipped = n_skipped,
+            n_epochs = 0,
+            log_loss = f64::NAN,
+            default_log_loss = f64::NAN
+        ));
+    }
+    
+    let n_items = items.len() as i32;
+    if n_items < min_items {
+        return Ok(list!(
+            parameters = Vec::<f64>::new(),
+            success = false,
+            error = error_list("not_enough_items", &format!(
+                "Only {} training items; at least {} are needed for stable parameters",
+                n_items, min_items
+            )),
+            n_items = n_items,
+            n_items_total = n_items_total,
+            n_cards = n_cards,
+            n_skipped = n_skipped,
+            n_epochs = 0,
+            log_loss = f64::NAN,
+            default_log_loss = f64::NAN
+        ));
+    }
+    
+    // Log loss on the training items before (defaults) and after fitting
+    let default_log_loss = training_log_loss(&DEFAULT_PARAMETERS, items.clone());
+    
+    let state = CombinedProgressState::new_shared();
+    
+    // Create input for compute_parameters
+    let input = ComputeParametersInput {
+        train_set: items,
+        progress: Some(state.clone()),
+        enable_short_term: enable_short_term,
+        ..Default::default()
+    };
+    
+    // Train on a worker thread so this (the R) thread can poll the progress
+    // state and invoke the R callback; R must only be called from here.
+    let handle = thread::spawn(move || in_pool(pool.as_ref(), || {
+        FSRS::new(Some(&DEFAULT_PARAMETERS)).and_then(|fsrs| fsrs.compute_parameters(input))
+    }));
+    
+    // The state is polled once more after training ends, so a run shorter
+    // than one poll interval still reports its progress
+    let mut last = (usize::MAX, usize::MAX);
+    loop {
+        let finished = handle.is_finished();
+        if let Some(callback) = &progress {
+            let current = {
+                let s = state.lock().unwrap();
+                (s.current(), s.total())
+            };
+            if current != last && current.1 > 0 {
+                last = current;
+                let res = callback.call(pairlist!(
+                    current = current.0 as f64,
+                    total = current.1 as f64
+                ));
+                if let Err(e) = res {
+                    state.lock().unwrap().want_abort = true;
+                    let _ = handle.join();
+                    return Err(e);
+                }
+            }
+        }
+        if finished {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    
+    let result = handle.join()
+        .map_err(|_| Error::Other("optimizer thread panicked".to_string()))?;
+    let n_epochs = state.lock().unwrap().splits.iter()
+        .map(|p| p.epoch_total)
+        .max()
+        .unwrap_or(0) as i32;
+    
+    match result {
+        Ok(output) => {
+            Ok(list!(
+                parameters = named_params(&output),
+                success = true,
+                error = Null::<String>,
+                n_items = n_items,
+                n_items_total = n_items_total,
+                n_cards = n_cards,
+                n_skipped = n_skipped,
+                n_epochs = n_epochs,
+                log_loss = training_log_loss(&output, kept_items()),
+                default_log_loss = default_log_loss
+            ))
+        },
+        Err(e) => {
+            Ok(list!(
+                parameters = Vec::<f64>::new(),
+                success = false,
+                error = error_to_list(&e),
+                n_items = n_items,
+                n_items_total = n_items_total,
+                n_cards = n_cards,
+                n_skipped = n_skipped,
+                n_epochs = n_epochs,
+                log_loss = f64::NAN,
+                default_log_loss = default_log_loss
+            ))
+        }
+    }
+}
+
+/// Only the pretrain step of `fsrs_optimize`: the initial stabilities w0-w3
+/// fitted to each card's first long-term review. fsrs-rs keeps pretraining
+/// private, but `compute_parameters` returns its result as soon as every
+/// training item is also a pretraining item, so only those are passed in.
+#[extendr]
+fn fsrs_pretrain(ratings: Vec<i32>, delta_ts: Vec<i32>, card_starts: Vec<i32>) -> Result<List> {
+    let items: Vec<FSRSItem> = build_items(&ratings, &delta_ts, &card_starts)
+        .into_iter()
+        .filter(|item| item.long_term_review_cnt() == 1)
+        .collect();
+    let n_items = items.len() as i32;
+    
+    let failed = |error: List| list!(
+        parameters = Vec::<f64>::new(),
+        success = false,
+        error = error,
+        n_items = n_items
+    );
+    
+    // compute_parameters drops outlier groups first and returns the defaults
+    // unfitted when fewer than 8 items survive, so that case is refused here
+    let keys: Vec<(i32, i32)> = items.iter()
+        .map(|item| (item.reviews[0].rating as i32, item.reviews[item.reviews.len() - 1].delta_t as i32))
+        .collect();
+    let removed = outlier_groups(&keys);
+    let n_kept = keys.iter().filter(|key| !removed.contains(key)).count();
+    if n_kept < 8 {
+        return Ok(failed(error_list("not_enough_items", &format!(
+            "Only {} of {} first-review items survive the outlier filter; pretraining needs at least 8",
+            n_kept, n_items
+        ))));
+    }
+    
+    let input = ComputeParametersInput {
+        train_set: items,
+        ..Default::default()
+    };
+    Ok(match FSRS::new(Some(&DEFAULT_PARAMETERS)).and_then(|fsrs| fsrs.compute_parameters(input)) {
+        Ok(w) => list!(
+            parameters = named_params(&w[..4]),
+            success = true,
+            error = Null::<String>,
+            n_items = n_items
+        ),
+        Err(e) => failed(error_to_list(&e)),
+    })
+}
+
+/// How many training items `fsrs_optimize` would build from the reviews, and
+/// from how many cards, without building them or training.
+#[extendr]
+fn fsrs_count_items(ratings: Vec<i32>, delta_ts: Vec<i32>, card_starts: Vec<i32>) -> List {
+    let counts = card_item_counts(&review_windows(&ratings, &delta_ts, &card_starts), &delta_ts);
+    let (n_items, n_cards, n_skipped) = item_totals(&counts);
+    list!(n_items = n_items, n_cards = n_cards, n_skipped = n_skipped)
+}
+
+/// Reconstruct one training item per review after a card's first, given
+/// reviews grouped by card and 1-based `card_starts`. Items ending in a
+/// same-day review (`delta_t == 0`) are skipped.
+fn build_items(ratings: &[i32], delta_ts: &[i32], card_starts: &[i32]) -> Vec<FSRSItem> {
+    build_card_items(ratings, delta_ts, card_starts).into_iter().flatten().collect()
+}
+
+/// Like `build_items`, but keeps each card's items together so callers can
+/// split or count by card. Entries line up with `card_starts`; a malformed
+/// window yields no items. `fsrs_optimize` builds its (possibly sampled)
+/// items from the same `review_windows` and `window_items`.
+fn build_card_items(ratings: &[i32], delta_ts: &[i32], card_starts: &[i32]) -> Vec<Vec<FSRSItem>> {
+    review_windows(ratings, delta_ts, card_starts)
+        .into_iter()
+        .map(|window| window.map_or_else(Vec::new, |(start, end)| window_items(ratings, delta_ts, start, end)))
+        .collect()
+}
+
+/// Training items for the card whose reviews occupy `start..end`: one per
+/// review after the first that is not on the same day as the previous one.
+fn window_items(ratings: &[i32], delta_ts: &[i32], start: usize, end: usize) -> Vec<FSRSItem> {
+    window_items_where(ratings, delta_ts, start, end, |_| true)
+}
+
+/// `window_items` limited to the items whose last review, as an index into
+/// the full review vectors, passes `keep`. Earlier reviews stay in each
+/// item's history either way.
+fn window_items_where(
+    ratings: &[i32],
+    delta_ts: &[i32],
+    start: usize,
+    end: usize,
+    keep: impl Fn(usize) -> bool
+) -> Vec<FSRSItem> {
+    let card_reviews = card_review_slice(ratings, delta_ts, start, end);
+    
+    let mut items: Vec<FSRSItem> = Vec::new();
+    for i in 2..=card_reviews.len() {
+        if card_reviews[i - 1].delta_t == 0 || !keep(start + i - 1) { continue; }
+        items.push(FSRSItem {
+            reviews: card_reviews[0..i].to_vec(),
+        });
+    }
+    items
+}
+
+/// Number of items `window_items` would build, without building them.
+fn window_item_count(delta_ts: &[i32], start: usize, end: usize) -> usize {
+    window_item_count_where(delta_ts, start, end, |_| true)
+}
+
+/// Number of items `window_items_where` would build with the same `keep`.
+fn window_item_count_where(delta_ts: &[i32], start: usize, end: usize, keep: impl Fn(usize) -> bool) -> usize {
+    (start + 1..end).filter(|&i| delta_ts[i] != 0 && keep(i)).count()
+}
+
+/// `window_item_count` for every card window, 0 for a malformed one.
+fn card_item_counts(windows: &[Option<(usize, usize)>], delta_ts: &[i32]) -> Vec<usize> {
+    windows.iter()
+        .map(|&w| w.map_or(0, |(start, end)| window_item_count(delta_ts, start, end)))
+        .collect()
+}
+
+/// Total items, cards yielding at least one, and cards skipped from
+/// per-card item counts. Cards whose reviews are all same-day, or whose
+/// window is malformed, yield no items.
+fn item_totals(counts: &[usize]) -> (i32, i32, i32) {
+    let n_cards = counts.iter().filter(|&&n| n > 0).count() as i32;
+    (counts.iter().sum::<usize>() as i32, n_cards, counts.len() as i32 - n_cards)
+}
+
+/// Indices `0..n` in a random order fixed by `seed`.
+fn seeded_order(n: usize, seed: i32) -> Vec<usize> {
+    let mut keys: Vec<(f64, usize)> = (0..n)
+        .map(|i| (uniform_from_seed(((seed as u64) << 32) | i as u64), i))
+        .collect();
+    keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+    keys.into_iter().map(|(_, i)| i).collect()
+}
+
+/// Chooses whole cards, given their item `counts`, holding at most
+/// `max_items` items. Cards are taken in a seeded random order and the
+/// sample stops at the first card that no longer fits: skipping it and
+/// filling the rest of the budget would favour short histories. The kept
+/// cards are thus a uniform random prefix, and no card's history is split.
+fn sample_cards(counts: &[usize], max_items: usize, seed: i32) -> Vec<bool> {
+    let mut keep = vec![false; counts.len()];
+    let mut budget = max_items;
+    for i in seeded_order(counts.len(), seed) {
+        if counts[i] > budget {
+            break;
+        }
+        keep[i] = counts[i] > 0;
+        budget -= counts[i];
+    }
+    keep
+}
+
+/// One `(start, end)` review range per entry of 1-based `card_starts`, or
+/// `None` for a malformed window, so callers can keep one result per card.
+fn card_windows(n_reviews: usize, card_starts: &[i32]) -> Vec<Option<(usize, usize)>> {
     // Convert card_starts to 0-based indices and add end marker
     let mut starts: Vec<usize> = card_starts.iter().map(|&x| (x - 1) as usize).collect();
-    starts.push(ratings.len());
+    starts.push(n_reviews);
+    
+    starts.windows(2)
+        .map(|w| (w[0] < w[1] && w[1] <= n_reviews).then_some((w[0], w[1])))
+        .collect()
+}
+
+/// A rayon pool of `n_threads` workers, or None to use the global pool
+/// (all cores) when `n_threads` is 0 or less.
+fn thread_pool(n_threads: i32) -> Result<Option<rayon::ThreadPool>> {
+    if n_threads <= 0 {
+        return Ok(None);
+    }
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(n_threads as usize)
+        .build()
+        .map(Some)
+        .map_err(|e| Error::Other(format!("cannot create a pool of {} threads: {}", n_threads, e)))
+}
+
+/// Runs `f` so that the crate's parallel work stays inside `pool`. `f` runs
+/// on a pool worker, so it must not call into R.
+fn in_pool<T: Send>(pool: Option<&rayon::ThreadPool>, f: impl FnOnce() -> T + Send) -> T {
+    match pool {
+        Some(pool) => pool.install(f),
+        None => f(),
+    }
+}
+
+/// `card_windows` over reviews given as parallel `ratings` and `delta_ts`.
+/// Only indices present in both count, so vectors of different lengths
+/// cannot be read out of bounds: reviews past the shorter one are ignored
+/// and cards starting there are malformed. The last card's window ends at
+/// the last review, inclusive.
+///
+/// A card with a rating outside 1-4 or a negative `delta_t` is malformed
+/// too. R's `NA_integer_` arrives as `i32::MIN`, which a cast to `u32`
+/// would turn into a huge interval, so this keeps it out of every item.
+fn review_windows(ratings: &[i32], delta_ts: &[i32], card_starts: &[i32]) -> Vec<Option<(usize, usize)>> {
+    card_windows(ratings.len().min(delta_ts.len()), card_starts)
+        .into_iter()
+        .map(|w| w.filter(|&(start, end)| {
+            (start..end).all(|i| (1..=4).contains(&ratings[i]) && delta_ts[i] >= 0)
+        }))
+        .collect()
+}
+
+fn card_review_slice(ratings: &[i32], delta_ts: &[i32], start: usize, end: usize) -> Vec<FSRSReview> {
+    (start..end)
+        .map(|i| FSRSReview {
+            rating: (ratings[i] as u32).min(4).max(1),
+            delta_t: delta_ts[i] as u32,
+        })
+        .collect()
+}
+
+fn training_log_loss(w: &[f32], items: Vec<FSRSItem>) -> f64 {
+    FSRS::new(Some(w))
+        .and_then(|fsrs| fsrs.evaluate(items, |_| true))
+        .map(|metrics| metrics.log_loss as f64)
+        .unwrap_or(f64::NAN)
+}
+
+#[extendr]
+fn fsrs_evaluate(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    card_starts: Vec<i32>,
+    params: Vec<f64>,
+    min_reviews: i32,
+    min_last_interval: i32,
+    n_bins: Option<i32>,
+    weighted: bool,
+    n_threads: i32
+) -> Result<List> {
+    let items = build_items(&ratings, &delta_ts, &card_starts);
+    let pool = thread_pool(n_threads)?;
+    evaluate_items(items, params, min_reviews, min_last_interval, n_bins, weighted, pool.as_ref())
+}
+
+/// Reconstructed items kept on the Rust side between calls, so a grid
+/// search can evaluate many parameter sets without rebuilding them.
+#[derive(Debug)]
+struct EvalSet {
+    items: Vec<FSRSItem>,
+}
+
+#[extendr]
+fn fsrs_build_eval_set(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    card_starts: Vec<i32>
+) -> ExternalPtr<EvalSet> {
+    // R owns the pointer; extendr's finalizer drops the items on collection
+    ExternalPtr::new(EvalSet { items: build_items(&ratings, &delta_ts, &card_starts) })
+}
+
+#[extendr]
+fn fsrs_eval_set_size(set: ExternalPtr<EvalSet>) -> i32 {
+    set.items.len() as i32
+}
+
+#[extendr]
+fn fsrs_evaluate_set(
+    set: ExternalPtr<EvalSet>,
+    params: Vec<f64>,
+    min_reviews: i32,
+    min_last_interval: i32,
+    n_bins: Option<i32>,
+    weighted: bool,
+    n_threads: i32
+) -> Result<List> {
+    let pool = thread_pool(n_threads)?;
+    evaluate_items(set.items.clone(), params, min_reviews, min_last_interval, n_bins, weighted, pool.as_ref())
+}
+
+/// Shared body of the evaluate functions. With `n_bins` NULL and `weighted`
+/// set, `rmse_bins` is the crate's own r_matrix metric; otherwise it is a
+/// different metric over equal-width bins of the per-item predictions.
+fn evaluate_items(
+    mut items: Vec<FSRSItem>,
+    params: Vec<f64>,
+    min_reviews: i32,
+    min_last_interval: i32,
+    n_bins: Option<i32>,
+    weighted: bool,
+    pool: Option<&rayon::ThreadPool>
+) -> Result<List> {
+    let decay = decay_from(&params_f32(Some(params.clone())));
+    let fsrs = create_fsrs(Some(params))?;
+    
+    // The closure fsrs-rs' evaluate takes is a progress callback, not a
+    // filter, so subsets are selected here before evaluating
+    items.retain(|item| {
+        item.reviews.len() >= min_reviews.max(0) as usize
+            && item.reviews.last().map_or(0, |r| r.delta_t) >= min_last_interval.max(0) as u32
+    });
+    let n_items = items.len() as i32;
+    
+    let empty_calibration = || list!(
+        predicted = Vec::<f64>::new(),
+        observed = Vec::<f64>::new(),
+        count = Vec::<i32>::new(),
+        weight = Vec::<f64>::new()
+    );
+    
+    let failed = |error: List| list!(
+        log_loss = f64::NAN,
+        rmse_bins = f64::NAN,
+        bin_edges = Null::<f64>,
+        success = false,
+        error = error,
+        n_items = n_items,
+        calibration = empty_calibration()
+    );
+    
+    if items.is_empty() {
+        return Ok(failed(error_list("no_data", "No items to evaluate")));
+    }
+    
+    let keys: Vec<_> = items.iter().map(r_matrix_index).collect();
+    // FSRS is not Sync, so the model moves into the pool with the items
+    // rather than being borrowed by its workers
+    let (predictions, evaluation) = in_pool(pool, move || {
+        let predictions = item_predictions(&fsrs, &items, decay);
+        (predictions, fsrs.evaluate(items, |_| true))
+    });
+    let (predicted, observed) = match predictions {
+        Ok(pairs) => pairs,
+        Err(e) => return Ok(failed(error_to_list(&e))),
+    };
+    let (bin_pred, bin_obs, bin_count, bin_weight) = calibration_bins(&keys, &predicted, &observed);
+    let calibration = list!(
+        predicted = bin_pred,
+        observed = bin_obs,
+        count = bin_count,
+        weight = bin_weight
+    );
+    let custom_rmse = match (n_bins, weighted) {
+        (None, true) => None,
+        (n, _) => Some(binned_rmse(&predicted, &observed, n.unwrap_or(20).max(1) as usize, weighted)),
+    };
+    
+    Ok(match evaluation {
+        Ok(metrics) => {
+            let (rmse_bins, bin_edges) = match custom_rmse {
+                Some((rmse, edges)) => (rmse, Robj::from(edges)),
+                None => (metrics.rmse_bins as f64, Robj::from(())),
+            };
+            list!(
+                log_loss = metrics.log_loss as f64,
+                rmse_bins = rmse_bins,
+                bin_edges = bin_edges,
+                success = true,
+                error = Null::<String>,
+                n_items = n_items,
+                calibration = calibration
+            )
+        },
+        Err(e) => failed(error_to_list(&e)),
+    })
+}
+
+#[extendr]
+fn fsrs_log_loss(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    card_starts: Vec<i32>,
+    params: Vec<f64>
+) -> Result<List> {
+    items_log_loss(&build_items(&ratings, &delta_ts, &card_starts), params)
+}
+
+#[extendr]
+fn fsrs_log_loss_set(set: ExternalPtr<EvalSet>, params: Vec<f64>) -> Result<List> {
+    items_log_loss(&set.items, params)
+}
+
+/// Log loss over `items` as fsrs-rs' `evaluate` computes it: unclamped
+/// binary cross-entropy averaged with the same recency weights, without the
+/// RMSE binning it also does.
+fn items_log_loss(items: &[FSRSItem], params: Vec<f64>) -> Result<List> {
+    let decay = decay_from(&params_f32(Some(params.clone())));
+    let fsrs = create_fsrs(Some(params))?;
+    let (predicted, observed) = item_predictions(&fsrs, items, decay)
+        .map_err(|e| Error::Other(format!("memory_state failed: {}", error_message(&e))))?;
+    let n_items = predicted.len();
+    let log_loss = if n_items == 0 {
+        f64::NAN
+    } else {
+        let weights = recency_weights(n_items);
+        -predicted.iter()
+            .zip(observed.iter())
+            .zip(weights.iter())
+            .map(|((&p, &y), &w)| w * (y * p.ln() + (1.0 - y) * (1.0 - p).ln()))
+            .sum::<f64>() / weights.iter().sum::<f64>()
+    };
+    Ok(list!(log_loss = log_loss, n_items = n_items as i32))
+}
+
+#[extendr]
+fn fsrs_metrics(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    card_starts: Vec<i32>,
+    params: Vec<f64>
+) -> Result<List> {
+    items_metrics(build_items(&ratings, &delta_ts, &card_starts), params)
+}
+
+#[extendr]
+fn fsrs_metrics_set(set: ExternalPtr<EvalSet>, params: Vec<f64>) -> Result<List> {
+    items_metrics(set.items.clone(), params)
+}
+
+/// Log loss and RMSE-bins as fsrs-rs' `evaluate` reports them, plus the
+/// Brier score and ROC AUC over the same per-item predictions with the same
+/// recency weights. All four are NaN without items; AUC is also NaN when
+/// every item passed or every item failed.
+fn items_metrics(items: Vec<FSRSItem>, params: Vec<f64>) -> Result<List> {
+    let decay = decay_from(&params_f32(Some(params.clone())));
+    let fsrs = create_fsrs(Some(params))?;
+    let (predicted, observed) = item_predictions(&fsrs, &items, decay)
+        .map_err(|e| Error::Other(format!("memory_state failed: {}", error_message(&e))))?;
+    let n_items = predicted.len();
+    if n_items == 0 {
+        return Ok(list!(
+            log_loss = f64::NAN,
+            rmse_bins = f64::NAN,
+            brier = f64::NAN,
+            auc = f64::NAN,
+            n_items = 0
+        ));
+    }
+    let metrics = fsrs.evaluate(items, |_| true)
+        .map_err(|e| Error::Other(format!("evaluate failed: {}", error_message(&e))))?;
+    let weights = recency_weights(n_items);
+    let brier = predicted.iter()
+        .zip(observed.iter())
+        .zip(weights.iter())
+        .map(|((p, y), w)| w * (p - y).powi(2))
+        .sum::<f64>() / weights.iter().sum::<f64>();
+    Ok(list!(
+        log_loss = metrics.log_loss as f64,
+        rmse_bins = metrics.rmse_bins as f64,
+        brier = brier,
+        auc = roc_auc(&predicted, &observed, &weights),
+        n_items = n_items as i32
+    ))
+}
+
+/// Log loss and RMSE-bins, as `items_metrics` reports them, within
+/// `n_strata` equal-width strata of difficulty over 1-10. Items are grouped
+/// by the difficulty their last review is predicted from, so the outcome
+/// being scored cannot move an item between strata. Empty strata get NaN.
+#[extendr]
+fn fsrs_metrics_by_difficulty(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    card_starts: Vec<i32>,
+    params: Vec<f64>,
+    n_strata: i32
+) -> Result<List> {
+    let fsrs = create_fsrs(Some(params))?;
+    let n_strata = n_strata.max(1) as usize;
+    let width = 9.0 / n_strata as f64;
+    
+    let mut strata: Vec<Vec<FSRSItem>> = vec![Vec::new(); n_strata];
+    for item in build_items(&ratings, &delta_ts, &card_starts) {
+        let history = FSRSItem { reviews: item.reviews[..item.reviews.len() - 1].to_vec() };
+        let state = fsrs.memory_state(history, None)
+            .map_err(|e| Error::Other(format!("memory_state failed: {}", error_message(&e))))?;
+        let d = clamp_difficulty(state.difficulty);
+        strata[(((d - 1.0) / width) as usize).min(n_strata - 1)].push(item);
+    }
+    
+    let mut n_items = Vec::with_capacity(n_strata);
+    let mut log_loss = Vec::with_capacity(n_strata);
+    let mut rmse_bins = Vec::with_capacity(n_strata);
+    for items in strata {
+        n_items.push(items.len() as i32);
+        if items.is_empty() {
+            log_loss.push(f64::NAN);
+            rmse_bins.push(f64::NAN);
+            continue;
+        }
+        let metrics = fsrs.evaluate(items, |_| true)
+            .map_err(|e| Error::Other(format!("evaluate failed: {}", error_message(&e))))?;
+        log_loss.push(metrics.log_loss as f64);
+        rmse_bins.push(metrics.rmse_bins as f64);
+    }
+    
+    Ok(list!(
+        lower = (0..n_strata).map(|i| 1.0 + width * i as f64).collect::<Vec<f64>>(),
+        upper = (0..n_strata).map(|i| 1.0 + width * (i + 1) as f64).collect::<Vec<f64>>(),
+        n_items = n_items,
+        log_loss = log_loss,
+        rmse_bins = rmse_bins
+    ))
+}
+
+#[extendr]
+fn fsrs_predictions(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    card_starts: Vec<i32>,
+    params: Vec<f64>
+) -> Result<List> {
+    let decay = decay_from(&params_f32(Some(params.clone())));
+    let fsrs = create_fsrs(Some(params))?;
     
-    // Build FSRSItems for each card
-    let mut items: Vec<FSRSItem> = Vec::new();
+    let mut card = Vec::new();
+    let mut review = Vec::new();
+    let mut elapsed_days = Vec::new();
+    let mut predicted = Vec::new();
+    let mut observed = Vec::new();
     
-    for window in starts.windows(2) {
-        let start = window[0];
-        let end = window[1];
-        
-        if start >= end || end > ratings.len() {
-            continue;
+    for (i, items) in build_card_items(&ratings, &delta_ts, &card_starts).into_iter().enumerate() {
+        let (p, o) = item_predictions(&fsrs, &items, decay)
+            .map_err(|e| Error::Other(format!("memory_state failed for card {}: {}", i + 1, error_message(&e))))?;
+        for item in &items {
+            card.push(i as i32 + 1);
+            review.push(item.reviews.len() as i32);
+            elapsed_days.push(item.reviews.last().map_or(0, |r| r.delta_t) as f64);
         }
-        
-        let card_reviews: Vec<FSRSReview> = (start..end)
-            .map(|i| FSRSReview {
-                rating: (ratings[i] as u32).min(4).max(1),
-                delta_t: delta_ts[i] as u32,
-            })
-            .collect();
-        
-        for i in 2..=card_reviews.len() {
-            if card_reviews[i - 1].delta_t == 0 { continue; }
-            items.push(FSRSItem {
-                reviews: card_reviews[0..i].to_vec(),
-            });
+        predicted.extend(p);
+        observed.extend(o);
+    }
+    
+    Ok(list!(
+        card = card,
+        review = review,
+        elapsed_days = elapsed_days,
+        predicted = predicted,
+        observed = observed
+    ))
+}
+
+/// Empirical pass rate over the reviews that become training items (every
+/// review after a card's first with `delta_t > 0`), restricted to those at
+/// least `min_interval` days after the previous review.
+#[extendr]
+fn fsrs_true_retention(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    card_starts: Vec<i32>,
+    min_interval: i32
+) -> List {
+    let min_interval = min_interval.max(1);
+    let mut n_passed = 0;
+    let mut n_failed = 0;
+    for (start, end) in review_windows(&ratings, &delta_ts, &card_starts).into_iter().flatten() {
+        for i in start + 1..end {
+            if delta_ts[i] < min_interval { continue; }
+            if ratings[i] > 1 { n_passed += 1; } else { n_failed += 1; }
         }
     }
+    let n_reviews = n_passed + n_failed;
     
+    list!(
+        retention = if n_reviews > 0 { n_passed as f64 / n_reviews as f64 } else { f64::na() },
+        n_reviews = n_reviews,
+        n_passed = n_passed,
+        n_failed = n_failed
+    )
+}
+
+/// Desired retention, out of `retentions`, at which the items' stabilities
+/// scheduled to whole days are predicted to pass at the observed rate.
+#[extendr]
+fn fsrs_calibrate_retention(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    card_starts: Vec<i32>,
+    params: Vec<f64>,
+    retentions: Vec<f64>
+) -> Result<List> {
+    let decay = decay_from(&params_f32(Some(params.clone())));
+    let factor = factor_from_decay(-decay);
+    let fsrs = create_fsrs(Some(params))?;
+    let items = build_items(&ratings, &delta_ts, &card_starts);
+    let n_items = items.len() as i32;
     if items.is_empty() {
-        return list!(
-            parameters = Vec::<f64>::new(),
-            success = false,
-            error = "No valid review data provided"
-        );
+        return Ok(list!(
+            desired_retention = f64::na(),
+            residual = f64::na(),
+            observed_retention = f64::na(),
+            predicted_retention = f64::na(),
+            n_items = 0
+        ));
     }
     
-    // Create input for compute_parameters
-    let input = ComputeParametersInput {
-        train_set: items,
-        enable_short_term: enable_short_term,
-        ..Default::default()
+    let mut stability = Vec::with_capacity(items.len());
+    let mut predicted = 0.0;
+    let mut observed = 0.0;
+    for item in &items {
+        let n = item.reviews.len();
+        let history = FSRSItem { reviews: item.reviews[..n - 1].to_vec() };
+        let last = &item.reviews[n - 1];
+        let s = fsrs.memory_state(history, None)
+            .map_err(|e| Error::Other(format!("memory_state failed: {}", error_message(&e))))?
+            .stability as f64;
+        predicted += forgetting_curve(s, last.delta_t as f64, decay);
+        if last.rating > 1 { observed += 1.0; }
+        stability.push(s);
+    }
+    let observed = observed / items.len() as f64;
+    
+    // Mean retrievability at the whole-day intervals scheduled at `r`
+    let scheduled = |r: f64| -> f64 {
+        let scale = r.powf(-1.0 / decay) - 1.0;
+        stability.iter()
+            .map(|&s| {
+                let interval = (s * scale / factor).round().max(1.0);
+                forgetting_curve(s, interval, decay)
+            })
+            .sum::<f64>() / stability.len() as f64
     };
+    let (best, residual) = retentions.iter()
+        .map(|&r| (r, scheduled(r) - observed))
+        .min_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+        .ok_or_else(|| Error::Other("retentions must not be empty".to_string()))?;
     
-    // Create a default FSRS instance and call compute_parameters on it
-    let fsrs = FSRS::new(Some(&DEFAULT_PARAMETERS)).unwrap();
+    Ok(list!(
+        desired_retention = best,
+        residual = residual,
+        observed_retention = observed,
+        predicted_retention = predicted / items.len() as f64,
+        n_items = n_items
+    ))
+}
+
+#[extendr]
+fn fsrs_compare_params(
+    ratings: Vec<i32>,
+    delta_ts: Vec<i32>,
+    card_starts: Vec<i32>,
+    params_a: Vec<f64>,
+    params_b: Vec<f64>
+) -> Result<List> {
+    let w_a = valid_params(Some(params_a))?;
+    let w_b = valid_params(Some(params_b))?;
+    let items = build_items(&ratings, &delta_ts, &card_starts);
+    let n_items = items.len() as i32;
     
-    match fsrs.compute_parameters(input) {
-        Ok(output) => {
-            list!(
-                parameters = output.iter().map(|&x| x as f64).collect::<Vec<_>>(),
-                success = true,
-                error = Null::<String>
-            )
-        },
-        Err(e) => {
-            list!(
-                parameters = Vec::<f64>::new(),
-                success = false,
-                error = format!("{:?}", e)
-            )
-        }
+    let failed = |error: List| list!(
+        log_loss_a = f64::NAN,
+        rmse_bins_a = f64::NAN,
+        log_loss_b = f64::NAN,
+        rmse_bins_b = f64::NAN,
+        delta_log_loss = f64::NAN,
+        delta_rmse_bins = f64::NAN,
+        n_items = n_items,
+        success = false,
+        error = error
+    );
+    
+    if items.is_empty() {
+        return Ok(failed(error_list("no_data", "No items to evaluate")));
     }
+    
+    let evaluate = |w: &[f32]| FSRS::new(Some(w)).and_then(|fsrs| fsrs.evaluate(items.clone(), |_| true));
+    let (a, b) = match (evaluate(&w_a), evaluate(&w_b)) {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(e), _) | (_, Err(e)) => return Ok(failed(error_to_list(&e))),
+    };
+    
+    Ok(list!(
+        log_loss_a = a.log_loss as f64,
+        rmse_bins_a = a.rmse_bins as f64,
+        log_loss_b = b.log_loss as f64,
+        rmse_bins_b = b.rmse_bins as f64,
+        delta_log_loss = (b.log_loss - a.log_loss) as f64,
+        delta_rmse_bins = (b.rmse_bins - a.rmse_bins) as f64,
+        n_items = n_items,
+        success = true,
+        error = Null::<String>
+    ))
 }
 
 #[extendr]
-fn fsrs_evaluate(
+fn fsrs_cross_validate(
     ratings: Vec<i32>,
     delta_ts: Vec<i32>,
     card_starts: Vec<i32>,
-    params: Vec<f64>
+    train_frac: f64,
+    seed: i32,
+    enable_short_term: bool
 ) -> List {
-    let fsrs = create_fsrs(Some(params));
+    let mut cards = build_card_items(&ratings, &delta_ts, &card_starts);
     
-    let mut starts: Vec<usize> = card_starts.iter().map(|&x| (x - 1) as usize).collect();
-    starts.push(ratings.len());
+    // Shuffle whole cards so no card's history lands on both sides
+    let order = seeded_order(cards.len(), seed);
+    let n_train_cards = ((cards.len() as f64) * train_frac).round() as usize;
     
-    let mut items: Vec<FSRSItem> = Vec::new();
+    // fsrs-rs weights items by position, so each split keeps its cards in
+    // their original order rather than the shuffled one
+    let (train_cards, test_cards) = order.split_at(n_train_cards.min(order.len()));
+    let mut split = |selected: &[usize]| {
+        let mut selected = selected.to_vec();
+        selected.sort_unstable();
+        selected.iter()
+            .flat_map(|&i| std::mem::take(&mut cards[i]))
+            .collect::<Vec<FSRSItem>>()
+    };
+    let train_set = split(train_cards);
+    let test_set = split(test_cards);
     
-    for window in starts.windows(2) {
-        let start = window[0];
-        let end = window[1];
-        
-        if start >= end || end > ratings.len() {
-            continue;
-        }
-        
-        let card_reviews: Vec<FSRSReview> = (start..end)
-            .map(|i| FSRSReview {
-                rating: (ratings[i] as u32).min(4).max(1),
-                delta_t: delta_ts[i] as u32,
-            })
-            .collect();
-        
-        for i in 2..=card_reviews.len() {
-            if card_reviews[i - 1].delta_t == 0 { continue; }
-            items.push(FSRSItem {
-                reviews: card_reviews[0..i].to_vec(),
-            });
-        }
+    let n_train = train_set.len() as i32;
+    let n_test = test_set.len() as i32;
+    let n_test_cards = (cards.len() - n_train_cards) as i32;
+    let n_train_cards = n_train_cards as i32;
+    
+    let failed = |error: List| list!(
+        parameters = Vec::<f64>::new(),
+        success = false,
+        error = error,
+        log_loss = f64::NAN,
+        rmse_bins = f64::NAN,
+        default_log_loss = f64::NAN,
+        default_rmse_bins = f64::NAN,
+        n_train = n_train,
+        n_test = n_test,
+        n_train_cards = n_train_cards,
+        n_test_cards = n_test_cards
+    );
+    
+    if train_set.is_empty() || test_set.is_empty() {
+        return failed(error_list("no_data", "Both the train and test split need at least one item"));
     }
     
-    if items.is_empty() {
-        return list!(
-            log_loss = f64::NAN,
-            rmse_bins = f64::NAN,
-            success = false
-        );
+    let input = ComputeParametersInput {
+        train_set,
+        enable_short_term,
+        ..Default::default()
+    };
+    let fitted = match FSRS::new(Some(&DEFAULT_PARAMETERS)).and_then(|fsrs| fsrs.compute_parameters(input)) {
+        Ok(w) => w,
+        Err(e) => return failed(error_to_list(&e)),
+    };
+    
+    // Defaults scored on the same held-out cards, as a baseline
+    let (default_log_loss, default_rmse_bins) = match FSRS::new(Some(&DEFAULT_PARAMETERS)).and_then(|fsrs| fsrs.evaluate(test_set.clone(), |_| true)) {
+        Ok(metrics) => (metrics.log_loss as f64, metrics.rmse_bins as f64),
+        Err(_) => (f64::NAN, f64::NAN),
+    };
+    
+    match FSRS::new(Some(&fitted)).and_then(|fsrs| fsrs.evaluate(test_set, |_| true)) {
+        Ok(metrics) => list!(
+            parameters = named_params(&fitted),
+            success = true,
+            error = Null::<String>,
+            log_loss = metrics.log_loss as f64,
+            rmse_bins = metrics.rmse_bins as f64,
+            default_log_loss = default_log_loss,
+            default_rmse_bins = default_rmse_bins,
+            n_train = n_train,
+            n_test = n_test,
+            n_train_cards = n_train_cards,
+            n_test_cards = n_test_cards
+        ),
+        Err(e) => failed(error_to_list(&e)),
     }
+}
+
+// ============================================================================
+// SIMULATION
+// ============================================================================
+
+#[extendr]
+fn fsrs_optimal_retention(
+    params: Option<Vec<f64>>,
+    deck_size: i32,
+    days: i32,
+    seed: Option<i32>
+) -> Result<List> {
+    let w = valid_params(params)?;
+    let fsrs = fsrs_from_weights(&w)?;
+    let config = simulator_config(deck_size, days);
     
-    match fsrs.evaluate(items, |_| true) {
-        Ok(metrics) => {
-            list!(
-                log_loss = metrics.log_loss as f64,
-                rmse_bins = metrics.rmse_bins as f64,
-                success = true
-            )
-        },
-        Err(_) => {
-            list!(
-                log_loss = f64::NAN,
-                rmse_bins = f64::NAN,
-                success = false
-            )
+    let retention = fsrs.optimal_retention(&config, &w, |_| true, None, None)
+        .map_err(|e| Error::Other(format!("optimal_retention failed: {}", error_message(&e))))?;
+    
+    // Re-run the simulator at the optimum to report what it costs
+    let result = simulate(&config, &w, retention, seed.map(|s| s as u64), None)
+        .map_err(|e| Error::Other(format!("simulate failed: {}", error_message(&e))))?;
+    
+    Ok(list!(
+        optimal_retention = retention as f64,
+        total_cost = result.cost_per_day.iter().map(|&c| c as f64).sum::<f64>(),
+        total_reviews = result.review_cnt_per_day.iter().sum::<usize>() as f64,
+        memorized = result.memorized_cnt_per_day.last().copied().unwrap_or(0.0) as f64
+    ))
+}
+
+/// Simulated workload and outcome of the same deck at each of `retentions`.
+#[extendr]
+fn fsrs_cost_sweep(
+    params: Option<Vec<f64>>,
+    deck_size: i32,
+    days: i32,
+    retentions: Vec<f64>,
+    seed: Option<i32>
+) -> Result<List> {
+    let w = valid_params(params)?;
+    let config = simulator_config(deck_size, days);
+    let n = retentions.len();
+    let mut total_reviews = Vec::with_capacity(n);
+    let mut total_cost = Vec::with_capacity(n);
+    let mut memorized = Vec::with_capacity(n);
+    
+    for &retention in &retentions {
+        let result = simulate(&config, &w, retention as f32, seed.map(|s| s as u64), None)
+            .map_err(|e| Error::Other(format!(
+                "simulate failed at retention {}: {}", retention, error_message(&e)
+            )))?;
+        total_reviews.push(result.review_cnt_per_day.iter().sum::<usize>() as f64);
+        total_cost.push(result.cost_per_day.iter().map(|&c| c as f64).sum::<f64>());
+        memorized.push(result.memorized_cnt_per_day.last().copied().unwrap_or(0.0) as f64);
+    }
+    
+    Ok(list!(
+        retention = retentions,
+        total_reviews = total_reviews,
+        total_cost = total_cost,
+        memorized = memorized
+    ))
+}
+
+/// Day-by-day output of one fsrs-rs simulation. The simulator draws from a
+/// `StdRng` seeded with `seed`, so equal seeds give identical output.
+/// Existing cards, given as parallel vectors, join the deck on day 0.
+#[extendr]
+fn fsrs_simulate_deck(
+    params: Option<Vec<f64>>,
+    desired_retention: f64,
+    deck_size: i32,
+    days: i32,
+    seed: i32,
+    existing_stability: Vec<f64>,
+    existing_difficulty: Vec<f64>,
+    existing_due: Vec<f64>,
+    knowledge_threshold: f64,
+    review_seconds: Option<Vec<f64>>,
+    new_per_day: Option<i32>,
+    max_reviews_per_day: Option<i32>
+) -> Result<List> {
+    let w = valid_params(params)?;
+    let mut config = simulator_config(deck_size, days);
+    if let Some(seconds) = review_seconds {
+        set_review_seconds(&mut config, &seconds)?;
+    }
+    // Unset limits stay at the simulator's unlimited defaults
+    if let Some(n) = new_per_day {
+        config.learn_limit = n.max(0) as usize;
+    }
+    if let Some(n) = max_reviews_per_day {
+        config.review_limit = n.max(0) as usize;
+    }
+    let existing = existing_cards(
+        &w, desired_retention, &existing_stability, &existing_difficulty, &existing_due
+    )?;
+    
+    // The simulator only reports summed retrievability per day, so log each
+    // card's (id, review day, stability) after every review, starting from
+    // the existing cards it keeps, to count cards above the threshold. The
+    // hook returns the interval unchanged, so the simulation is unaffected.
+    let log: Arc<Mutex<Vec<(i64, f32, f32)>>> = Arc::new(Mutex::new(
+        existing.iter()
+            .filter(|card| card.stability > 1e-9)
+            .map(|card| (card.id, card.last_date, card.stability))
+            .collect()
+    ));
+    let sink = Arc::clone(&log);
+    config.post_scheduling_fn = Some(PostSchedulingFn(Arc::new(move |card, _, day, _, _| {
+        sink.lock().unwrap().push((card.id, day as f32, card.stability));
+        card.interval
+    })));
+    
+    let existing = if existing.is_empty() { None } else { Some(existing) };
+    let result = simulate(&config, &w, desired_retention as f32, Some(seed as u64), existing)
+        .map_err(|e| Error::Other(format!("simulate failed: {}", error_message(&e))))?;
+    let memorized_count = cards_above_threshold(
+        &log.lock().unwrap(), config.learn_span, decay_from(&w), knowledge_threshold
+    );
+    
+    Ok(list!(
+        day = (1..=result.review_cnt_per_day.len() as i32).collect::<Vec<_>>(),
+        reviews = result.review_cnt_per_day.iter().map(|&n| n as i32).collect::<Vec<_>>(),
+        recalled = result.correct_cnt_per_day.iter().map(|&n| n as i32).collect::<Vec<_>>(),
+        learned = result.learn_cnt_per_day.iter().map(|&n| n as i32).collect::<Vec<_>>(),
+        memorized = result.memorized_cnt_per_day.iter().map(|&m| m as f64).collect::<Vec<_>>(),
+        memorized_count = memorized_count,
+        cost = result.cost_per_day.iter().map(|&c| c as f64).collect::<Vec<_>>()
+    ))
+}
+
+/// Cards per day whose retrievability is at least `threshold`, from a log of
+/// `(id, review day, stability)`: each stability holds from its review day
+/// until the card's next review or the end of the simulation. Days are
+/// indexed the way the simulator sums `memorized_cnt_per_day`.
+fn cards_above_threshold(log: &[(i64, f32, f32)], days: usize, decay: f64, threshold: f64) -> Vec<i32> {
+    let mut log = log.to_vec();
+    log.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    let mut counts = vec![0; days];
+    for (k, &(id, start, stability)) in log.iter().enumerate() {
+        let end = match log.get(k + 1) {
+            Some(&(next_id, next_day, _)) if next_id == id => (next_day as usize).min(days),
+            _ => days,
+        };
+        for day in (start.max(0.0) as usize)..end {
+            let elapsed = day as f64 - start as f64;
+            if forgetting_curve(stability as f64, elapsed, decay) >= threshold {
+                counts[day] += 1;
+            }
         }
     }
+    counts
+}
+
+/// Runs `n_reps` simulations with seeds derived from `base_seed` and
+/// summarises daily review counts across them.
+#[extendr]
+fn fsrs_simulate_replicates(
+    params: Option<Vec<f64>>,
+    desired_retention: f64,
+    deck_size: i32,
+    days: i32,
+    n_reps: i32,
+    base_seed: i32
+) -> Result<List> {
+    let w = valid_params(params)?;
+    let config = simulator_config(deck_size, days);
+    let seeds: Vec<i32> = (0..n_reps.max(1) as u64)
+        .map(|rep| derive_seed(base_seed as u64, rep))
+        .collect();
+    
+    let mut runs: Vec<Vec<f64>> = Vec::with_capacity(seeds.len());
+    for &seed in &seeds {
+        let result = simulate(&config, &w, desired_retention as f32, Some(seed as u64), None)
+            .map_err(|e| Error::Other(format!(
+                "simulate failed for seed {}: {}", seed, error_message(&e)
+            )))?;
+        runs.push(result.review_cnt_per_day.iter().map(|&n| n as f64).collect());
+    }
+    
+    let n_days = runs.iter().map(|r| r.len()).min().unwrap_or(0);
+    let n = runs.len() as f64;
+    let mut mean_reviews = Vec::with_capacity(n_days);
+    let mut sd_reviews = Vec::with_capacity(n_days);
+    for day in 0..n_days {
+        let mean = runs.iter().map(|r| r[day]).sum::<f64>() / n;
+        mean_reviews.push(mean);
+        // Sample standard deviation, NA for a single replicate as in R's sd()
+        sd_reviews.push(if runs.len() < 2 {
+            f64::na()
+        } else {
+            (runs.iter().map(|r| (r[day] - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+        });
+    }
+    
+    Ok(list!(
+        day = (1..=n_days as i32).collect::<Vec<_>>(),
+        mean_reviews = mean_reviews,
+        sd_reviews = sd_reviews,
+        seeds = seeds
+    ))
+}
+
+/// Reviews a card starting at each `stability` receives within `days` when
+/// every review is passed ("Good") on its rounded scheduled day.
+#[extendr]
+fn fsrs_expected_reviews(
+    stability: Vec<f64>,
+    desired_retention: f64,
+    days: f64,
+    params: Option<Vec<f64>>
+) -> Result<Vec<f64>> {
+    let fsrs = create_fsrs(params)?;
+    let retention = desired_retention as f32;
+    // No difficulty is given, so start from that of a new card rated Good
+    let difficulty = next_states(&fsrs, None, retention, 0)?.good.memory.difficulty;
+    
+    stability.iter()
+        .map(|&s| {
+            if s.is_na() {
+                return Ok(f64::na());
+            }
+            let mut state = MemoryState { stability: s as f32, difficulty };
+            let mut elapsed = 0.0;
+            let mut reviews = 0.0;
+            loop {
+                let interval = fsrs.next_interval(Some(state.stability), retention, 0)
+                    .round()
+                    .max(1.0) as f64;
+                if elapsed + interval > days {
+                    break;
+                }
+                elapsed += interval;
+                reviews += 1.0;
+                state = next_states(&fsrs, Some(state), retention, interval as u32)?.good.memory;
+            }
+            Ok(reviews)
+        })
+        .collect()
 }
 
 // ============================================================================
 // HELPER
 // ============================================================================
 
-fn create_fsrs(params: Option<Vec<f64>>) -> FSRS {
+fn params_f32(params: Option<Vec<f64>>) -> Vec<f32> {
     match params {
-        Some(p) => {
-            let params_f32: Vec<f32> = p.iter().map(|&x| x as f32).collect();
-            FSRS::new(Some(&params_f32)).unwrap()
-        },
-        None => FSRS::new(Some(&DEFAULT_PARAMETERS)).unwrap()
+        Some(p) => p.iter().map(|&x| x as f32).collect(),
+        None => DEFAULT_PARAMETERS.to_vec()
+    }
+}
+
+/// `params_f32`, rejected with a message R users can act on when it is not
+/// a 17, 19 or 21 parameter vector of finite numbers.
+fn valid_params(params: Option<Vec<f64>>) -> Result<Vec<f32>> {
+    let w = params_f32(params);
+    if !matches!(w.len(), 17 | 19 | 21) {
+        return Err(Error::Other(format!(
+            "params must be 17, 19 or 21 finite numbers, got {} values", w.len()
+        )));
+    }
+    if let Some(i) = w.iter().position(|x| !x.is_finite()) {
+        return Err(Error::Other(format!("params[{}] must be finite, got {}", i + 1, w[i])));
+    }
+    Ok(w)
+}
+
+fn fsrs_from_weights(w: &[f32]) -> Result<FSRS> {
+    FSRS::new(Some(w))
+        .map_err(|e| Error::Other(format!("invalid FSRS parameters: {}", error_message(&e))))
+}
+
+fn create_fsrs(params: Option<Vec<f64>>) -> Result<FSRS> {
+    fsrs_from_weights(&valid_params(params)?)
+}
+
+/// fsrs-rs `next_states`, with a failure reported against the memory state
+/// and elapsed days that caused it instead of panicking.
+fn next_states(
+    fsrs: &FSRS,
+    state: Option<MemoryState>,
+    desired_retention: f32,
+    days: u32
+) -> Result<fsrs::NextStates> {
+    fsrs.next_states(state, desired_retention, days).map_err(|e| {
+        let input = match state {
+            Some(s) => format!(
+                "stability {}, difficulty {} after {} days", s.stability, s.difficulty, days
+            ),
+            None => "a new card".to_string(),
+        };
+        Error::Other(format!("next_states failed for {}: {}", input, error_message(&e)))
+    })
+}
+
+/// `next_states` for callers that only want the memory states. fsrs-rs keeps
+/// no desired retention on the `FSRS` object: `next_states` takes one per
+/// call and uses it only to fill each outcome's `interval`, which these
+/// callers discard, so any value gives the same states. Anything that
+/// reports an interval takes the retention from its caller instead.
+fn memory_states(fsrs: &FSRS, state: Option<MemoryState>, days: u32) -> Result<fsrs::NextStates> {
+    next_states(fsrs, state, 0.9, days)
+}
+
+fn memory_state_from_sm2(
+    fsrs: &FSRS,
+    ease_factor: f64,
+    interval: f64,
+    sm2_retention: f64
+) -> Result<MemoryState> {
+    fsrs.memory_state_from_sm2(ease_factor as f32, interval as f32, sm2_retention as f32)
+        .map_err(|e| Error::Other(format!(
+            "memory_state_from_sm2 failed for ease_factor {}, interval {}: {}",
+            ease_factor, interval, error_message(&e)
+        )))
+}
+
+/// Numeric R vector named `w0`, `w1`, ... to match FSRS parameter indexing.
+fn named_params(params: &[f32]) -> Robj {
+    let values: Vec<f64> = params.iter().map(|&x| x as f64).collect();
+    let names: Vec<String> = (0..params.len()).map(|i| format!("w{}", i)).collect();
+    let mut robj = Robj::from(values);
+    robj.set_names(names).unwrap();
+    robj
+}
+
+/// Stable machine-readable code for a crate error, so R code can branch on
+/// `result$error$code` instead of parsing messages.
+#[allow(unreachable_patterns)]
+fn error_code(e: &FSRSError) -> &'static str {
+    match e {
+        FSRSError::NotEnoughData => "not_enough_data",
+        FSRSError::OptimalNotFound => "optimal_not_found",
+        FSRSError::Interrupted => "interrupted",
+        FSRSError::InvalidParameters => "invalid_parameters",
+        FSRSError::InvalidInput => "invalid_input",
+        FSRSError::InvalidDeckSize => "invalid_deck_size",
+        _ => "fsrs_error",
+    }
+}
+
+#[allow(unreachable_patterns)]
+fn error_message(e: &FSRSError) -> String {
+    match e {
+        FSRSError::NotEnoughData => "Not enough review data to train on".to_string(),
+        FSRSError::OptimalNotFound => "No optimum was found".to_string(),
+        FSRSError::Interrupted => "Interrupted before finishing".to_string(),
+        FSRSError::InvalidParameters => "Invalid FSRS parameters".to_string(),
+        FSRSError::InvalidInput => "Invalid review input".to_string(),
+        FSRSError::InvalidDeckSize => "Invalid deck size".to_string(),
+        _ => e.to_string(),
+    }
+}
+
+fn error_to_list(e: &FSRSError) -> List {
+    error_list(error_code(e), &error_message(e))
+}
+
+fn error_list(code: &str, message: &str) -> List {
+    list!(code = code, message = message)
+}
+
+/// Parameters in the FSRS-5/6 form fsrs-rs computes with: FSRS-4.5 vectors
+/// have their initial-difficulty and difficulty-step weights converted the
+/// way fsrs-rs converts them on load.
+fn filled_params(w: &[f32]) -> Vec<f32> {
+    let mut w = w.to_vec();
+    if w.len() == 17 {
+        w[4] = w[5].mul_add(2.0, w[4]);
+        w[5] = w[5].mul_add(3.0, 1.0).ln() / 3.0;
+        w[6] += 0.5;
+    }
+    w
+}
+
+/// fsrs-rs clamps difficulty to [1, 10] in f32; clamp again after widening
+/// so rounding can never report a value just outside the documented range.
+fn clamp_difficulty(difficulty: f32) -> f64 {
+    (difficulty as f64).clamp(1.0, 10.0)
+}
+
+/// Memory state after rating a card `days` after `state` (None for a new
+/// card). fsrs-rs has no long-term-only mode outside training, so without
+/// `enable_short_term` a same-day review leaves an existing state unchanged,
+/// as in FSRS-4.5 (see fsrs_next_state).
+fn review_state(
+    fsrs: &FSRS,
+    state: Option<MemoryState>,
+    rating: u32,
+    days: u32,
+    enable_short_term: bool
+) -> Result<MemoryState> {
+    match state {
+        Some(current) if days == 0 && !enable_short_term => Ok(current),
+        _ => Ok(memory_for_rating(&memory_states(fsrs, state, days)?, rating)),
+    }
+}
+
+fn check_precision(precision: &str) -> Result<()> {
+    if !matches!(precision, "f32" | "f64") {
+        return Err(Error::Other(format!(
+            "precision must be \"f32\" or \"f64\", not \"{}\"", precision
+        )));
+    }
+    Ok(())
+}
+
+/// Weights padded to 21 as fsrs-rs `check_and_fill_parameters` does and
+/// clipped as `FSRS::new` does, but kept in f64 for the `precision = "f64"`
+/// replays.
+fn filled_params_f64(params: Option<Vec<f64>>) -> Result<Vec<f64>> {
+    valid_params(params.clone())?;
+    let mut w = params.unwrap_or_else(|| DEFAULT_PARAMETERS.iter().map(|&x| x as f64).collect());
+    if w.len() == 17 {
+        w[4] = w[5].mul_add(2.0, w[4]);
+        w[5] = w[5].mul_add(3.0, 1.0).ln() / 3.0;
+        w[6] += 0.5;
+        w.extend([0.0, 0.0]);
+    }
+    if w.len() == 19 {
+        w.extend([0.0, 0.5]);
+    }
+    // The model clips without short-term memory, so w19 may reach 0
+    for (x, (low, high)) in w.iter_mut().zip(parameter_bounds(21, false)) {
+        *x = x.clamp(low, high);
+    }
+    Ok(w)
+}
+
+/// Unrounded interval at which recall falls to `desired_retention`, the f64
+/// counterpart of fsrs-rs `next_interval`.
+fn interval_f64(stability: f64, desired_retention: f64, decay: f64) -> f64 {
+    stability / factor_from_decay(-decay) * (desired_retention.powf(-1.0 / decay) - 1.0)
+}
+
+/// f64 port of the fsrs-rs model step (`Model::step`) behind `review_state`,
+/// with the same same-day handling, so a replay does not round stability
+/// and difficulty to f32 after every review. `w` must hold 21 weights.
+fn review_state_f64(
+    w: &[f64],
+    state: Option<(f64, f64)>,
+    rating: u32,
+    days: u32,
+    enable_short_term: bool
+) -> (f64, f64) {
+    let g = rating as f64;
+    let init_difficulty = |g: f64| w[4] - (w[5] * (g - 1.0)).exp() + 1.0;
+    let (s, d) = match state {
+        None => {
+            return (
+                w[rating as usize - 1].clamp(0.001, 36500.0),
+                init_difficulty(g).clamp(1.0, 10.0)
+            );
+        }
+        Some(current) if days == 0 && !enable_short_term => return current,
+        Some((s, d)) => (s.clamp(0.001, 36500.0), d.clamp(1.0, 10.0)),
+    };
+    
+    let new_s = if days == 0 {
+        let sinc = (w[17] * (g - 3.0 + w[18])).exp() * s.powf(-w[19]);
+        s * if rating >= 3 { sinc.max(1.0) } else { sinc }
+    } else {
+        let r = forgetting_curve(s, days as f64, w[20]);
+        if rating == 1 {
+            let forgotten = w[11] * d.powf(-w[12]) * ((s + 1.0).powf(w[13]) - 1.0)
+                * ((1.0 - r) * w[14]).exp();
+            forgotten.min(s / (w[17] * w[18]).exp())
+        } else {
+            let hard_penalty = if rating == 2 { w[15] } else { 1.0 };
+            let easy_bonus = if rating == 4 { w[16] } else { 1.0 };
+            s * (w[8].exp() * (11.0 - d) * s.powf(-w[9]) * (((1.0 - r) * w[10]).exp() - 1.0)
+                * hard_penalty * easy_bonus + 1.0)
+        }
+    };
+    // Linear damping towards 10, then mean reversion to the Easy anchor
+    let damped = d + (10.0 - d) * (-w[6] * (g - 3.0)) / 9.0;
+    let new_d = w[7] * (init_difficulty(4.0) - damped) + damped;
+    (new_s.clamp(0.001, 36500.0), new_d.clamp(1.0, 10.0))
+}
+
+/// Replays reviews one at a time from `state` (None for a new card), as in
+/// fsrs_memory_state_trajectory, so the stability floor applies to every
+/// intermediate state and a collapsed (or NaN) stability cannot feed into
+/// the next review. Returns the final state and how many reviews were
+/// clamped to `floor`.
+fn replay_reviews(
+    fsrs: &FSRS,
+    mut state: Option<MemoryState>,
+    ratings: &[i32],
+    delta_ts: &[i32],
+    floor: f32,
+    enable_short_term: bool
+) -> Result<(Option<MemoryState>, i32)> {
+    let mut n_clamped = 0;
+    for (i, (&r, &t)) in ratings.iter().zip(delta_ts.iter()).enumerate() {
+        let days = if state.is_some() { t.max(0) as u32 } else { 0 };
+        let mut next = review_state(fsrs, state, (r as u32).min(4).max(1), days, enable_short_term)
+            .map_err(|e| Error::Other(format!("review {}: {}", i + 1, e)))?;
+        if !(next.stability >= floor) {
+            next.stability = floor;
+            n_clamped += 1;
+        }
+        state = Some(next);
+    }
+    Ok((state, n_clamped))
+}
+
+fn memory_for_rating(states: &fsrs::NextStates, rating: u32) -> MemoryState {
+    match rating {
+        1 => states.again.memory,
+        2 => states.hard.memory,
+        3 => states.good.memory,
+        4 => states.easy.memory,
+        _ => states.good.memory,
+    }
+}
+
+/// Forgetting-curve decay: the 21st FSRS-6 parameter, clipped to its bounds
+/// as the fsrs-rs model does, or the fixed 0.5 of FSRS-5 (19 parameters) and
+/// FSRS-4.5 (17), which fsrs-rs pads to 21.
+fn decay_from(w: &[f32]) -> f64 {
+    let (low, high) = PARAMETER_BOUNDS[20];
+    w.get(20).map(|&d| (d as f64).clamp(low, high)).unwrap_or(0.5)
+}
+
+/// `FACTOR = 0.9^(1 / decay) - 1` for the curve `R = (1 + FACTOR * t / S)^decay`,
+/// chosen so that R = 0.9 when t = S. `decay` is the negative exponent, i.e.
+/// `-decay_from(w)`; 19/81 for the FSRS-5 decay of -0.5.
+fn factor_from_decay(decay: f64) -> f64 {
+    0.9f64.powf(1.0 / decay) - 1.0
+}
+
+fn forgetting_curve(stability: f64, elapsed_days: f64, decay: f64) -> f64 {
+    (1.0 + factor_from_decay(-decay) * elapsed_days / stability).powf(-decay)
+}
+
+/// Predicted retrievability before each item's last review, and whether that
+/// review was a pass (rating > 1) -- the pair `evaluate` scores internally.
+fn item_predictions(
+    fsrs: &FSRS,
+    items: &[FSRSItem],
+    decay: f64
+) -> fsrs::Result<(Vec<f64>, Vec<f64>)> {
+    let pairs = items.iter()
+        .map(|item| {
+            let n = item.reviews.len();
+            let history = FSRSItem { reviews: item.reviews[..n - 1].to_vec() };
+            let last = &item.reviews[n - 1];
+            let state = fsrs.memory_state(history, None)?;
+            let r = forgetting_curve(state.stability as f64, last.delta_t as f64, decay);
+            Ok((r, if last.rating > 1 { 1.0 } else { 0.0 }))
+        })
+        .collect::<fsrs::Result<Vec<_>>>()?;
+    Ok(pairs.into_iter().unzip())
+}
+
+/// Per-item weights fsrs-rs' `evaluate` and `compute_parameters` apply by
+/// position: 0.25 for the first item rising to 1 for the last.
+fn recency_weights(n: usize) -> Vec<f64> {
+    let length = (n as f64 - 1.0).max(1.0);
+    (0..n).map(|i| 0.25 + 0.75 * (i as f64 / length).powi(3)).collect()
+}
+
+/// The bin fsrs-rs' `evaluate` puts an item in for its RMSE: log-spaced
+/// classes of the predicted review's `delta_t`, of the number of long-term
+/// reviews, and of the lapses before it.
+fn r_matrix_index(item: &FSRSItem) -> (u32, u32, u32) {
+    let n = item.reviews.len();
+    let delta_t = item.reviews[n - 1].delta_t as f64;
+    let delta_t_bin = (2.48 * 3.62f64.powf(delta_t.log(3.62).floor()) * 100.0).round() as u32;
+    let length = item.reviews.iter().filter(|r| r.delta_t > 0).count() as f64 + 1.0;
+    let length_bin = (1.99 * 1.89f64.powf(length.log(1.89).floor())).round() as u32;
+    let lapse = item.reviews[..n - 1].iter()
+        .filter(|r| r.rating == 1 && r.delta_t > 0)
+        .count();
+    if lapse == 0 {
+        return (delta_t_bin, length_bin, 0);
+    }
+    let lapse_bin = (1.65 * 1.73f64.powf((lapse as f64).log(1.73).floor())).round() as u32;
+    (delta_t_bin, length_bin, lapse_bin)
+}
+
+/// Groups predictions by their `r_matrix_index` keys, the bins fsrs-rs'
+/// `evaluate` uses for its RMSE, and returns the mean predicted and observed retention, count and summed
+/// recency weight per bin. The weighted RMSE of predicted against observed
+/// over these rows is `rmse_bins`.
+fn calibration_bins(
+    keys: &[(u32, u32, u32)],
+    predicted: &[f64],
+    observed: &[f64]
+) -> (Vec<f64>, Vec<f64>, Vec<i32>, Vec<f64>) {
+    let mut bins = BTreeMap::new();
+    let weights = recency_weights(keys.len());
+    for (((key, p), o), w) in keys.iter().zip(predicted).zip(observed).zip(weights) {
+        let entry = bins.entry(*key).or_insert((0.0, 0.0, 0, 0.0));
+        entry.0 += p;
+        entry.1 += o;
+        entry.2 += 1;
+        entry.3 += w;
+    }
+    let mut bin_pred = Vec::with_capacity(bins.len());
+    let mut bin_obs = Vec::with_capacity(bins.len());
+    let mut bin_count = Vec::with_capacity(bins.len());
+    let mut bin_weight = Vec::with_capacity(bins.len());
+    for (sum_p, sum_o, n, w) in bins.into_values() {
+        bin_pred.push(sum_p / n as f64);
+        bin_obs.push(sum_o / n as f64);
+        bin_count.push(n);
+        bin_weight.push(w);
+    }
+    (bin_pred, bin_obs, bin_count, bin_weight)
+}
+
+/// RMSE between mean predicted and observed retention over `n_bins`
+/// equal-width bins of predicted retrievability, plus the bin edges. Weighted
+/// bins contribute in proportion to their item count, unweighted ones equally.
+/// Unlike `rmse_bins` from `evaluate`, items are not recency-weighted.
+fn binned_rmse(predicted: &[f64], observed: &[f64], n_bins: usize, weighted: bool) -> (f64, Vec<f64>) {
+    let mut bins = vec![(0.0, 0.0, 0usize); n_bins];
+    for (p, o) in predicted.iter().zip(observed.iter()) {
+        let bin = &mut bins[((p * n_bins as f64).floor().max(0.0) as usize).min(n_bins - 1)];
+        bin.0 += p;
+        bin.1 += o;
+        bin.2 += 1;
+    }
+    let (mut sum_sq, mut total_weight) = (0.0, 0.0);
+    for &(sum_p, sum_o, n) in bins.iter().filter(|b| b.2 > 0) {
+        let weight = if weighted { n as f64 } else { 1.0 };
+        sum_sq += weight * ((sum_p - sum_o) / n as f64).powi(2);
+        total_weight += weight;
+    }
+    let edges = (0..=n_bins).map(|i| i as f64 / n_bins as f64).collect();
+    ((sum_sq / total_weight).sqrt(), edges)
+}
+
+/// Area under the ROC curve of `predicted` for the passes in `observed`,
+/// with each pass-failure pair counted by the product of their `weights`
+/// and tied predictions counting half. NaN unless there is at least one
+/// pass and one failure.
+fn roc_auc(predicted: &[f64], observed: &[f64], weights: &[f64]) -> f64 {
+    let mut order: Vec<usize> = (0..predicted.len()).collect();
+    order.sort_by(|&a, &b| predicted[a].total_cmp(&predicted[b]));
+    let (mut concordant, mut pass_total, mut fail_below) = (0.0, 0.0, 0.0);
+    let mut i = 0;
+    while i < order.len() {
+        let mut j = i;
+        while j + 1 < order.len() && predicted[order[j + 1]] == predicted[order[i]] {
+            j += 1;
+        }
+        let (mut pass_w, mut fail_w) = (0.0, 0.0);
+        for &k in &order[i..=j] {
+            if observed[k] > 0.5 {
+                pass_w += weights[k];
+            } else {
+                fail_w += weights[k];
+            }
+        }
+        // Passes beat every failure ranked below them and tie with the
+        // failures sharing their prediction
+        concordant += pass_w * (fail_below + fail_w / 2.0);
+        pass_total += pass_w;
+        fail_below += fail_w;
+        i = j + 1;
+    }
+    if pass_total == 0.0 || fail_below == 0.0 {
+        return f64::NAN;
+    }
+    concordant / (pass_total * fail_below)
+}
+
+/// Fuzz ranges used by FSRS schedulers: (start, end, factor). Each range
+/// widens the fuzz window by `factor` per day of interval inside it.
+const FUZZ_RANGES: [(f64, f64, f64); 3] = [
+    (2.5, 7.0, 0.15),
+    (7.0, 20.0, 0.1),
+    (20.0, f64::INFINITY, 0.05),
+];
+
+/// Picks a whole-day interval from the fuzz window around `interval`,
+/// using `fuzz_factor` in [0, 1). Intervals below 2.5 days are not fuzzed.
+fn fuzz_interval(interval: f64, fuzz_factor: f64) -> i32 {
+    if interval < 2.5 {
+        return interval.round().max(1.0) as i32;
+    }
+    let delta = 1.0 + FUZZ_RANGES.iter()
+        .map(|&(start, end, factor)| factor * (interval.min(end) - start).max(0.0))
+        .sum::<f64>();
+    let min_ivl = (interval - delta).round().max(2.0);
+    let max_ivl = (interval + delta).round();
+    (fuzz_factor * (max_ivl - min_ivl + 1.0) + min_ivl).floor() as i32
+}
+
+/// One SplitMix64 draw mapped to [0, 1); enough randomness for a fuzz factor
+/// without pulling in an RNG crate.
+fn uniform_from_seed(seed: u64) -> f64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Simulator cards for a user's current collection. The simulator needs each
+/// card's last review day, which is recovered by assuming the card was
+/// scheduled at `desired_retention`: last review = due - interval(stability).
+fn existing_cards(
+    w: &[f32],
+    desired_retention: f64,
+    stability: &[f64],
+    difficulty: &[f64],
+    due: &[f64]
+) -> Result<Vec<SimulatorCard>> {
+    if difficulty.len() != stability.len() || due.len() != stability.len() {
+        return Err(Error::Other(format!(
+            "existing card vectors must have equal lengths (stability {}, difficulty {}, due {})",
+            stability.len(), difficulty.len(), due.len()
+        )));
+    }
+    let fsrs = fsrs_from_weights(w)?;
+    Ok((0..stability.len())
+        .map(|i| {
+            let s = stability[i] as f32;
+            let interval = fsrs.next_interval(Some(s), desired_retention as f32, 0).max(1.0);
+            SimulatorCard {
+                // The simulator numbers its new cards 0, -1, -2, ..., so
+                // positive ids keep existing cards apart from them
+                id: i as i64 + 1,
+                difficulty: difficulty[i] as f32,
+                stability: s,
+                last_date: due[i] as f32 - interval,
+                due: due[i] as f32,
+                interval,
+                lapses: 0,
+            }
+        })
+        .collect())
+}
+
+/// Seed for replicate `index` of a run started from `base_seed`: a SplitMix64
+/// draw scaled to a non-negative R integer, so any single replicate can be
+/// rerun on its own.
+fn derive_seed(base_seed: u64, index: u64) -> i32 {
+    (uniform_from_seed((base_seed << 32) | index) * i32::MAX as f64) as i32
+}
+
+fn simulator_config(deck_size: i32, days: i32) -> SimulatorConfig {
+    SimulatorConfig {
+        deck_size: deck_size.max(1) as usize,
+        learn_span: days.max(1) as usize,
+        ..Default::default()
+    }
+}
+
+/// Replaces the simulator's per-review costs with `seconds`, ordered Again,
+/// Hard, Good, Easy (reviews) then one cost for every learning and
+/// relearning step, the rows of `state_rating_costs`.
+fn set_review_seconds(config: &mut SimulatorConfig, seconds: &[f64]) -> Result<()> {
+    if seconds.len() != 5 || seconds.iter().any(|&x| !(x >= 0.0) || !x.is_finite()) {
+        return Err(Error::Other(format!(
+            "review_seconds must be 5 finite non-negative numbers (again, hard, good, easy, learn), got {:?}",
+            seconds
+        )));
     }
+    let learn = seconds[4] as f32;
+    config.state_rating_costs = [
+        [learn; 4],
+        [seconds[0] as f32, seconds[1] as f32, seconds[2] as f32, seconds[3] as f32],
+        [learn; 4],
+    ];
+    Ok(())
 }
 
 // ============================================================================
@@ -360,14 +3160,73 @@ fn create_fsrs(params: Option<Vec<f64>>) -> FSRS {
 extendr_module! {
     mod rfsrs;
     fn fsrs_default_parameters;
+    fn fsrs_version;
+    fn fsrs_parameter_bounds;
+    fn fsrs_clamp_parameters;
     fn fsrs_next_interval;
+    fn fsrs_intervals_for_retentions;
+    fn fsrs_next_interval_fuzz;
     fn fsrs_initial_state;
+    fn fsrs_new_card_interval;
+    fn fsrs_initial_states_all;
     fn fsrs_next_state;
+    fn fsrs_stability_multipliers;
     fn fsrs_repeat;
+    fn fsrs_model_new;
+    fn fsrs_model_params;
+    fn fsrs_model_free;
+    fn fsrs_model_initial_state;
+    fn fsrs_model_next_interval;
+    fn fsrs_model_next_state;
+    fn fsrs_model_repeat;
+    fn fsrs_apply_learning_steps;
+    fn fsrs_apply_relearning;
+    fn fsrs_interval_sequence;
+    fn fsrs_default_ladder;
+    fn fsrs_difficulty_components;
     fn fsrs_retrievability;
     fn fsrs_retrievability_vec;
+    fn fsrs_elapsed_days;
+    fn fsrs_deck_retrievability;
+    fn fsrs_difficulty_histogram;
+    fn fsrs_retention_at_interval;
+    fn fsrs_forgetting_curve;
+    fn fsrs_factor_from_decay;
+    fn fsrs_stability_for_interval;
+    fn fsrs_half_life;
     fn fsrs_from_sm2;
+    fn fsrs_from_sm2_vec;
+    fn fsrs_effective_ease;
     fn fsrs_memory_state;
+    fn fsrs_replay_prefix;
+    fn fsrs_from_sm2_then_replay;
+    fn fsrs_memory_state_batch;
+    fn fsrs_reschedule;
+    fn fsrs_memory_state_trajectory;
+    fn fsrs_card_starts;
+    fn fsrs_delta_days;
+    fn fsrs_build_items;
+    fn fsrs_flag_outliers;
     fn fsrs_optimize;
+    fn fsrs_pretrain;
+    fn fsrs_count_items;
     fn fsrs_evaluate;
+    fn fsrs_build_eval_set;
+    fn fsrs_eval_set_size;
+    fn fsrs_evaluate_set;
+    fn fsrs_log_loss;
+    fn fsrs_log_loss_set;
+    fn fsrs_metrics;
+    fn fsrs_metrics_set;
+    fn fsrs_metrics_by_difficulty;
+    fn fsrs_predictions;
+    fn fsrs_true_retention;
+    fn fsrs_calibrate_retention;
+    fn fsrs_compare_params;
+    fn fsrs_cross_validate;
+    fn fsrs_optimal_retention;
+    fn fsrs_cost_sweep;
+    fn fsrs_simulate_deck;
+    fn fsrs_simulate_replicates;
+    fn fsrs_expected_reviews;
 }